@@ -2,10 +2,24 @@ ENTRY(_start)
 SECTIONS
 {
     . = 0x41000000;
+    __kernel_start = .;
     .text.boot : { *(.text.boot) }
     .text : { *(.text, .text.*) }
-    .data : { *(.data, .data.*) }
+
+    /* Sections are page aligned so that the MMU can give each its own permissions */
+    . = ALIGN(4096);
+    __text_end = .;
     .rodata : { *(.rodata, .rodata.*) }
+    /* Filled in with the symbol table after linking, see `make kernel` */
+    .ksyms : {
+        __ksyms_start = .;
+        KEEP(*(.ksyms))
+        __ksyms_end = .;
+    }
+
+    . = ALIGN(4096);
+    __rodata_end = .;
+    .data : { *(.data, .data.*) }
     .bss : { *(.bss, .bss.*) }
 
     . = ALIGN(8);
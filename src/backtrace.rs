@@ -0,0 +1,157 @@
+use crate::device_tree::Node;
+use crate::mmu;
+use core::arch::asm;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// Most frames printed, in case the chain of frame records loops.
+const MAX_DEPTH: usize = 64;
+/// Furthest apart two frame records on one stack can be, which is the size of the boot stack.
+const MAX_FRAME_SIZE: usize = 0x40000;
+
+extern "C" {
+    static __ksyms_start: u8;
+    static __ksyms_end: u8;
+}
+
+/// Space for the symbol table, filled in after linking by `make kernel`. It is lines of
+/// `<16 hex digit address> <name>\n` sorted by address, followed by zeros.
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; 256 * 1024] = [0; 256 * 1024];
+
+static WAIT_FOR_DEBUGGER: AtomicBool = AtomicBool::new(false);
+
+/// Reads `panic=wait` from the `bootargs` of `/chosen`, which keeps a panicked kernel around
+/// for a debugger instead of powering off.
+pub fn init(root: &Node) {
+    let wait = root
+        .child_by_name("chosen")
+        .and_then(|chosen| chosen.prop_by_name("bootargs"))
+        .map_or(false, |args| {
+            args.value
+                .split(|c| *c == b' ' || *c == 0)
+                .any(|arg| arg == b"panic=wait")
+        });
+    WAIT_FOR_DEBUGGER.store(wait, Ordering::Relaxed);
+}
+
+pub fn wait_for_debugger() -> bool {
+    WAIT_FOR_DEBUGGER.load(Ordering::Relaxed)
+}
+
+/// The frame pointer of the function this is inlined into.
+#[inline(always)]
+pub fn frame_pointer() -> usize {
+    let fp: usize;
+    unsafe { asm!("mov {}, x29", out(reg) fp) };
+    fp
+}
+
+/// Walks the chain of `(previous fp, lr)` frame records starting at `fp`, yielding the return
+/// address of each frame.
+pub struct Frames {
+    fp: usize,
+    depth: usize,
+}
+
+impl Frames {
+    pub fn new(fp: usize) -> Self {
+        Frames { fp, depth: 0 }
+    }
+}
+
+impl Iterator for Frames {
+    type Item = usize;
+    fn next(&mut self) -> Option<usize> {
+        if self.fp == 0 || self.fp % 16 != 0 || self.depth == MAX_DEPTH {
+            return None;
+        }
+        // The first fp may come from a trapped register, so make sure reading it can't fault.
+        // A record is 16 aligned, so it never straddles two pages.
+        if !mmu::accessible(self.fp, false) {
+            return None;
+        }
+        let record = self.fp as *const usize;
+        let (prev, lr) = unsafe { (record.read(), record.add(1).read()) };
+        // Callers' frames are always further up the same stack, anything else is not a frame
+        // record and must not be followed.
+        self.fp = if prev > self.fp && prev - self.fp <= MAX_FRAME_SIZE {
+            prev
+        } else {
+            0
+        };
+        self.depth += 1;
+        if lr == 0 {
+            return None;
+        }
+        Some(lr)
+    }
+}
+
+fn symbol_table() -> &'static [u8] {
+    unsafe {
+        let start = &__ksyms_start as *const u8;
+        let len = &__ksyms_end as *const u8 as usize - start as usize;
+        let table = core::slice::from_raw_parts(start, len);
+        let used = table.iter().position(|c| *c == 0).unwrap_or(len);
+        &table[..used]
+    }
+}
+
+/// The function containing `pc` and how far into it `pc` is.
+pub fn symbolize(pc: usize) -> Option<(&'static str, usize)> {
+    let (start, text_end, _, _) = mmu::kernel_image();
+    if pc < start || pc >= text_end {
+        return None;
+    }
+    let mut best = None;
+    for line in symbol_table().split(|c| *c == b'\n') {
+        if line.len() < 18 {
+            continue;
+        }
+        let addr = core::str::from_utf8(&line[..16])
+            .ok()
+            .and_then(|addr| usize::from_str_radix(addr, 16).ok());
+        match addr {
+            Some(addr) if addr <= pc => best = Some((addr, &line[17..])),
+            // The table is sorted, so nothing later can contain `pc`.
+            Some(_) => break,
+            None => continue,
+        }
+    }
+    let (addr, name) = best?;
+    let name = core::str::from_utf8(name).ok()?;
+    Some((name, pc - addr))
+}
+
+fn print_frame(out: &mut impl Write, i: usize, pc: usize) -> fmt::Result {
+    write!(out, "  #{:<2} {:#018x}", i, pc)?;
+    match symbolize(pc) {
+        Some((name, offset)) => writeln!(out, " {}+{:#x}", name, offset),
+        None => writeln!(out),
+    }
+}
+
+/// Prints the frames above `fp`, starting with the one at `pc` if there is one.
+pub fn print(out: &mut impl Write, pc: Option<usize>, fp: usize) -> fmt::Result {
+    writeln!(out, "Backtrace:")?;
+    let mut i = 0;
+    if let Some(pc) = pc {
+        print_frame(out, i, pc)?;
+        i += 1;
+    }
+    for lr in Frames::new(fp) {
+        // The return address is after the `bl`, which may be the last instruction of the
+        // caller, so look up the call itself.
+        print_frame(out, i, lr.saturating_sub(4))?;
+        i += 1;
+    }
+    if symbol_table().is_empty() {
+        writeln!(
+            out,
+            "(no symbol table, build with `make kernel` to get names)"
+        )?;
+    }
+    Ok(())
+}
@@ -1,4 +1,5 @@
 use crate::bit_array::{nearest_div_8, BitArray};
+use alloc::vec::Vec;
 
 pub trait BlockDevice {
     const NUM_BLOCKS: usize;
@@ -7,6 +8,24 @@ pub trait BlockDevice {
     fn read(&mut self, block_num: u32, dst: &mut [u8]) -> Result<usize, ()>;
     /// Write to a block on this device from src. Returns number of bytes written.
     fn write(&mut self, block_num: u32, src: &[u8]) -> Result<usize, ()>;
+    /// Reads each `(block_num, dst)` pair, which devices that can have many requests in flight
+    /// do all at once. Returns the total number of bytes read.
+    fn read_many(&mut self, reqs: &mut [(u32, &mut [u8])]) -> Result<usize, ()> {
+        let mut read = 0;
+        for (block_num, dst) in reqs.iter_mut() {
+            read += self.read(*block_num, dst)?;
+        }
+        Ok(read)
+    }
+    /// Writes each `(block_num, src)` pair, which devices that can have many requests in flight
+    /// do all at once. Returns the total number of bytes written.
+    fn write_many(&mut self, reqs: &[(u32, &[u8])]) -> Result<usize, ()> {
+        let mut written = 0;
+        for &(block_num, src) in reqs {
+            written += self.write(block_num, src)?;
+        }
+        Ok(written)
+    }
     /// Perform initialization of this block device
     fn init(&mut self) {}
 }
@@ -309,16 +328,16 @@ where
         self.block_device.init();
 
         let mut buf = [0; OWN_BLOCKS * B::BLOCK_SIZE];
-        for i in 0..OWN_BLOCKS {
-            let read = self
-                .block_device
-                .read(
-                    i as u32,
-                    &mut buf[i * B::BLOCK_SIZE..(i + 1) * B::BLOCK_SIZE],
-                )
-                .map_err(|_| InitErr::FailedToRead)?;
-            assert_eq!(read, B::BLOCK_SIZE);
-        }
+        let mut reqs: Vec<_> = buf
+            .chunks_mut(B::BLOCK_SIZE)
+            .enumerate()
+            .map(|(i, dst)| (i as u32, dst))
+            .collect();
+        let read = self
+            .block_device
+            .read_many(&mut reqs)
+            .map_err(|_| InitErr::FailedToRead)?;
+        assert_eq!(read, buf.len());
         if u32::from_ne_bytes([buf[0], buf[1], buf[2], buf[3]]) != MAGIC_NUMBER {
             return self.persist().map_err(InitErr::Persist);
         }
@@ -389,13 +408,16 @@ where
             curr += len;
         }
         assert!(curr <= buf.len(), "Wrote past end of buffer without panic?");
-        for i in 0..OWN_BLOCKS {
-            let written = self
-                .block_device
-                .write(i as u32, &buf[i * B::BLOCK_SIZE..(i + 1) * B::BLOCK_SIZE])
-                .map_err(|_| PersistErr::FailedToWrite)?;
-            assert_eq!(written, B::BLOCK_SIZE);
-        }
+        let reqs: Vec<_> = buf
+            .chunks(B::BLOCK_SIZE)
+            .enumerate()
+            .map(|(i, src)| (i as u32, src))
+            .collect();
+        let written = self
+            .block_device
+            .write_many(&reqs)
+            .map_err(|_| PersistErr::FailedToWrite)?;
+        assert_eq!(written, buf.len());
         Ok(())
     }
 
@@ -539,6 +561,46 @@ where
 
         self.block_device.write(b_n, src)
     }
+
+    /// Reads from the `n`th block of the metadata handle on into dst, one block per
+    /// `BLOCK_SIZE` of it, with all of the reads in flight at once.
+    pub fn read_many(
+        &mut self,
+        MetadataHandle(i): MetadataHandle,
+        n: usize,
+        dst: &mut [u8],
+    ) -> Result<usize, ()> {
+        let i = i as usize;
+        let md = self.stored.get(i).ok_or(())?;
+        let md = md.as_ref().ok_or(())?;
+        let mut blocks = md.owned().skip(n);
+        let mut reqs = Vec::with_capacity((dst.len() + B::BLOCK_SIZE - 1) / B::BLOCK_SIZE);
+        for chunk in dst.chunks_mut(B::BLOCK_SIZE) {
+            reqs.push((blocks.next().ok_or(())?, chunk));
+        }
+
+        self.block_device.read_many(&mut reqs)
+    }
+
+    /// Writes to the `n`th block of the metadata handle on from src, one block per
+    /// `BLOCK_SIZE` of it, with all of the writes in flight at once.
+    pub fn write_many(
+        &mut self,
+        MetadataHandle(i): MetadataHandle,
+        n: usize,
+        src: &[u8],
+    ) -> Result<usize, ()> {
+        let i = i as usize;
+        let md = &self.stored.get(i).ok_or(())?;
+        let md = md.as_ref().ok_or(())?;
+        let mut blocks = md.owned().skip(n);
+        let mut reqs = Vec::with_capacity((src.len() + B::BLOCK_SIZE - 1) / B::BLOCK_SIZE);
+        for chunk in src.chunks(B::BLOCK_SIZE) {
+            reqs.push((blocks.next().ok_or(())?, chunk));
+        }
+
+        self.block_device.write_many(&reqs)
+    }
     #[allow(dead_code)]
     fn own_required_blocks(&self) -> usize {
         let num_bytes = core::mem::size_of::<u32>()
@@ -42,6 +42,23 @@ impl DeviceTree {
     pub fn root(&self) -> Option<Node> {
         self.nodes().next()
     }
+
+    /// Size in bytes of the whole blob, including the strings block.
+    pub fn total_size(&self) -> usize {
+        self.total_size.native() as usize
+    }
+
+    /// Iterates over the `(address, size)` entries of the memory reservation block.
+    pub fn memory_reservations(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let base = unsafe {
+            (self as *const _ as *const u8).offset(self.memory_reserve_map_offset.native() as isize)
+                as *const Endian<u64, Big>
+        };
+        (0..)
+            .map(move |i| unsafe { ((*base.add(i * 2)).native(), (*base.add(i * 2 + 1)).native()) })
+            // The block is terminated by an entry of all zeroes
+            .take_while(|&(addr, size)| addr != 0 || size != 0)
+    }
 }
 
 #[repr(C)]
@@ -57,6 +74,58 @@ pub struct Prop<'a> {
     pub value: &'a [u8],
 }
 
+impl<'a> Prop<'a> {
+    /// Iterates over the big-endian u32 cells of this property.
+    pub fn cells(&self) -> impl Iterator<Item = u32> + 'a {
+        self.value
+            .chunks_exact(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+    }
+
+    /// Interprets this property as a single u32 cell.
+    pub fn as_u32(&self) -> Option<u32> {
+        self.cells().next()
+    }
+
+    /// Iterates over the null separated strings of a string list property.
+    pub fn strings(&self) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let value = self.value.strip_suffix(&[0]).unwrap_or(self.value);
+        value.split(|c| *c == 0)
+    }
+
+    /// Returns whether a string list property, such as `compatible`, contains `s`.
+    pub fn contains_str(&self, s: &str) -> bool {
+        self.strings().any(|v| v == s.as_bytes())
+    }
+
+    /// Splits a `reg` style property into `(address, size)` pairs.
+    pub fn reg(
+        &self,
+        address_cells: usize,
+        size_cells: usize,
+    ) -> impl Iterator<Item = (usize, usize)> + 'a {
+        self.value
+            .chunks_exact((address_cells + size_cells) * 4)
+            .map(move |chunk| {
+                let (addr, rest) = regs_to_usize(chunk, address_cells);
+                let (size, _) = regs_to_usize(rest, size_cells);
+                (addr, size)
+            })
+    }
+}
+
+/// Reads `cell_size` big-endian cells from the front of `regs`, returning the value and the rest.
+pub fn regs_to_usize(regs: &[u8], cell_size: usize) -> (usize, &[u8]) {
+    let mut result = 0;
+    let (work, rest) = regs.split_at(cell_size * 4);
+    for chunk in work.chunks(4) {
+        let mut c = [0; 4];
+        c.copy_from_slice(chunk);
+        result = result << 32 | (u32::from_be_bytes(c) as usize);
+    }
+    (result, rest)
+}
+
 pub struct PropIterator<'a> {
     struct_base: *const BE,
     strings_base: *const u8,
@@ -125,11 +194,41 @@ impl<'a> Node<'a> {
         }
     }
 
-    pub fn prop_by_name(&self, name: &str) -> Option<Prop> {
+    pub fn prop_by_name(&self, name: &str) -> Option<Prop<'a>> {
         let name = name.as_bytes();
         self.props().find(|prop| prop.name == name)
     }
 
+    /// The `#address-cells` this node specifies for its children.
+    pub fn address_cells(&self) -> usize {
+        self.prop_by_name("#address-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(2) as usize
+    }
+
+    /// The `#size-cells` this node specifies for its children.
+    pub fn size_cells(&self) -> usize {
+        self.prop_by_name("#size-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(1) as usize
+    }
+
+    /// Returns whether this node's `compatible` property lists `compat`.
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.prop_by_name("compatible")
+            .map(|p| p.contains_str(compat))
+            .unwrap_or(false)
+    }
+
+    /// Searches this node and everything below it for the node with the given `phandle`.
+    pub fn node_by_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        if self.prop_by_name("phandle").and_then(|p| p.as_u32()) == Some(phandle) {
+            return Some(*self);
+        }
+        self.children()
+            .find_map(|child| child.node_by_phandle(phandle))
+    }
+
     pub fn children(&self) -> NodeIterator<'a> {
         NodeIterator {
             struct_base: self.base,
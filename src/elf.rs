@@ -0,0 +1,291 @@
+use crate::fs::{self, FileDescriptor, FileMode, SeekFrom};
+use crate::mmu::{self, Attributes};
+use crate::process::{self, KernelFs, Pid, Process, ProcessErr};
+use alloc::{string::String, vec::Vec};
+use core::arch::asm;
+use core::convert::TryFrom;
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_64: u8 = 2;
+const DATA_LE: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const MACHINE_AARCH64: u16 = 183;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+/// More program headers than this is not something we expect from a statically linked binary.
+const MAX_PROGRAM_HEADERS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub ty: u16,
+    pub machine: u16,
+    pub entry: u64,
+    pub phoff: u64,
+    pub phentsize: u16,
+    pub phnum: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub ty: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ElfErr {
+    Open(fs::OpenErr),
+    Read,
+    /// The file ended before a header or segment did.
+    Truncated,
+    BadMagic,
+    Not64Bit,
+    NotLittleEndian,
+    NotExecutable,
+    WrongMachine(u16),
+    BadProgramHeaders,
+    /// A segment lies outside the user half of the address space.
+    BadSegment {
+        vaddr: u64,
+        memsz: u64,
+    },
+    Process(ProcessErr),
+}
+
+impl From<ProcessErr> for ElfErr {
+    fn from(e: ProcessErr) -> Self {
+        ElfErr::Process(e)
+    }
+}
+
+fn u16_at(b: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([b[i], b[i + 1]])
+}
+
+fn u32_at(b: &[u8], i: usize) -> u32 {
+    let mut v = [0; 4];
+    v.copy_from_slice(&b[i..i + 4]);
+    u32::from_le_bytes(v)
+}
+
+fn u64_at(b: &[u8], i: usize) -> u64 {
+    let mut v = [0; 8];
+    v.copy_from_slice(&b[i..i + 8]);
+    u64::from_le_bytes(v)
+}
+
+impl Header {
+    /// Parses and validates the ELF header of an AArch64 executable.
+    pub fn parse(b: &[u8; HEADER_SIZE]) -> Result<Self, ElfErr> {
+        if b[..4] != MAGIC {
+            return Err(ElfErr::BadMagic);
+        }
+        if b[4] != CLASS_64 {
+            return Err(ElfErr::Not64Bit);
+        }
+        if b[5] != DATA_LE {
+            return Err(ElfErr::NotLittleEndian);
+        }
+        let header = Header {
+            ty: u16_at(b, 16),
+            machine: u16_at(b, 18),
+            entry: u64_at(b, 24),
+            phoff: u64_at(b, 32),
+            phentsize: u16_at(b, 54),
+            phnum: u16_at(b, 56),
+        };
+        if header.ty != TYPE_EXEC {
+            return Err(ElfErr::NotExecutable);
+        }
+        if header.machine != MACHINE_AARCH64 {
+            return Err(ElfErr::WrongMachine(header.machine));
+        }
+        if (header.phentsize as usize) < PROGRAM_HEADER_SIZE
+            || header.phnum as usize > MAX_PROGRAM_HEADERS
+        {
+            return Err(ElfErr::BadProgramHeaders);
+        }
+        Ok(header)
+    }
+}
+
+impl ProgramHeader {
+    pub fn parse(b: &[u8]) -> Self {
+        ProgramHeader {
+            ty: u32_at(b, 0),
+            flags: u32_at(b, 4),
+            offset: u64_at(b, 8),
+            vaddr: u64_at(b, 16),
+            filesz: u64_at(b, 32),
+            memsz: u64_at(b, 40),
+        }
+    }
+
+    fn attributes(&self) -> Attributes {
+        if self.flags & PF_X != 0 {
+            Attributes::USER_TEXT
+        } else if self.flags & PF_W != 0 {
+            Attributes::USER_DATA
+        } else {
+            Attributes::USER_RODATA
+        }
+    }
+}
+
+/// Reads exactly `dst.len()` bytes starting at `offset`.
+fn read_at(
+    fs: &mut KernelFs,
+    fd: FileDescriptor,
+    offset: u64,
+    dst: &mut [u8],
+) -> Result<(), ElfErr> {
+    let offset = u32::try_from(offset).map_err(|_| ElfErr::Truncated)?;
+    fs.seek(fd, SeekFrom::Start(offset))
+        .map_err(|_| ElfErr::Read)?;
+    let mut done = 0;
+    while done < dst.len() {
+        match fs.read(fd, &mut dst[done..]) {
+            Ok(0) => return Err(ElfErr::Truncated),
+            Ok(n) => done += n,
+            Err(_) => return Err(ElfErr::Read),
+        }
+    }
+    Ok(())
+}
+
+/// Maps every `PT_LOAD` segment of the executable open at `fd` into `process`, returning the
+/// entry point.
+fn load_segments(
+    fs: &mut KernelFs,
+    fd: FileDescriptor,
+    process: &mut Process,
+) -> Result<usize, ElfErr> {
+    let size = fs.stat(fd).map_err(|_| ElfErr::Read)?.size as u64;
+    let mut header = [0; HEADER_SIZE];
+    read_at(fs, fd, 0, &mut header)?;
+    let header = Header::parse(&header)?;
+
+    let mut phdrs = Vec::with_capacity(header.phnum as usize);
+    let mut buf = [0; PROGRAM_HEADER_SIZE];
+    for i in 0..header.phnum as u64 {
+        let offset = i
+            .checked_mul(header.phentsize as u64)
+            .and_then(|off| off.checked_add(header.phoff))
+            .ok_or(ElfErr::BadProgramHeaders)?;
+        read_at(fs, fd, offset, &mut buf)?;
+        phdrs.push(ProgramHeader::parse(&buf));
+    }
+
+    for phdr in phdrs.iter().filter(|p| p.ty == PT_LOAD) {
+        let in_user = phdr.vaddr >= mmu::USER_START as u64
+            && phdr
+                .vaddr
+                .checked_add(phdr.memsz)
+                .map_or(false, |end| end <= mmu::USER_END as u64);
+        if !in_user || phdr.filesz > phdr.memsz {
+            return Err(ElfErr::BadSegment {
+                vaddr: phdr.vaddr,
+                memsz: phdr.memsz,
+            });
+        }
+        if phdr
+            .offset
+            .checked_add(phdr.filesz)
+            .map_or(true, |end| end > size)
+        {
+            return Err(ElfErr::Truncated);
+        }
+        // Whatever is past `filesz` is .bss, which the fresh zeroed pages already cover.
+        process.map_zeroed(phdr.vaddr as usize, phdr.memsz as usize, phdr.attributes())?;
+        let mut chunk = [0; 512];
+        let mut done = 0;
+        while done < phdr.filesz {
+            let n = (phdr.filesz - done).min(chunk.len() as u64) as usize;
+            read_at(fs, fd, phdr.offset + done, &mut chunk[..n])?;
+            process.write((phdr.vaddr + done) as usize, &chunk[..n])?;
+            done += n as u64;
+        }
+        if phdr.flags & PF_X != 0 {
+            // The code was written through the data cache, which instruction fetches miss.
+            process.clean_dcache(phdr.vaddr as usize, phdr.filesz as usize)?;
+        }
+    }
+    // Make sure nothing stale is fetched from the segments.
+    unsafe { asm!("dsb ish", "ic iallu", "dsb ish", "isb") };
+    Ok(header.entry as usize)
+}
+
+/// Copies `argv` and `envp` onto the top of the user stack, laid out as
+/// `argc, argv[0..], NULL, envp[0..], NULL` followed by the strings themselves.
+///
+/// Returns the new stack pointer along with the addresses of argv and envp.
+fn push_args(
+    process: &mut Process,
+    mut sp: usize,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<(usize, usize, usize), ProcessErr> {
+    let mut ptrs = Vec::with_capacity(argv.len() + envp.len());
+    for s in argv.iter().chain(envp) {
+        sp -= s.len() + 1;
+        process.write(sp, s.as_bytes())?;
+        process.write(sp + s.len(), &[0])?;
+        ptrs.push(sp as u64);
+    }
+    let (argv_ptrs, envp_ptrs) = ptrs.split_at(argv.len());
+
+    let words = 1 + argv.len() + 1 + envp.len() + 1;
+    sp = (sp - words * 8) & !0xf;
+    let mut at = sp;
+    let mut push = |v: u64| -> Result<(), ProcessErr> {
+        process.write(at, &v.to_le_bytes())?;
+        at += 8;
+        Ok(())
+    };
+    push(argv.len() as u64)?;
+    for &p in argv_ptrs {
+        push(p)?;
+    }
+    push(0)?;
+    for &p in envp_ptrs {
+        push(p)?;
+    }
+    push(0)?;
+    let argv_addr = sp + 8;
+    Ok((sp, argv_addr, argv_addr + (argv.len() + 1) * 8))
+}
+
+/// Loads the executable at `path`, relative to `dir`, into a new process and starts it with
+/// x0 = argc, x1 = argv and x2 = envp.
+pub fn exec(
+    fs: &mut KernelFs,
+    dir: FileDescriptor,
+    path: &[&str],
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<Pid, ElfErr> {
+    let fd = fs
+        .open(dir, path, FileMode::MustExist)
+        .map_err(ElfErr::Open)?;
+    let name = path.last().copied().unwrap_or_default();
+    let loaded = Process::new(String::from(name))
+        .map_err(ElfErr::from)
+        .and_then(|mut process| {
+            let entry = load_segments(fs, fd, &mut process)?;
+            let sp = process.map_stack()?;
+            let (sp, argv, envp) = push_args(&mut process, sp, argv, envp)?;
+            Ok((process, entry, sp, argv, envp))
+        });
+    let _ = fs.close(fd);
+    let (process, entry, sp, argv_addr, envp_addr) = loaded?;
+    let args = [argv.len() as u64, argv_addr as u64, envp_addr as u64];
+    Ok(process::spawn(process, entry, sp, &args)?)
+}
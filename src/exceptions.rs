@@ -0,0 +1,313 @@
+use crate::{backtrace, log, warn};
+use core::arch::{asm, global_asm};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(target_arch = "aarch64")]
+global_asm!(include_str!("exceptions.S"));
+
+/// Register state spilled by the vector table on every exception. The layout must match
+/// `exceptions.S`.
+#[derive(Debug, Clone, Copy)]
+#[repr(C, align(16))]
+pub struct TrapFrame {
+    /// x0 through x30
+    pub regs: [u64; 31],
+    pub sp_el0: u64,
+    pub elr: u64,
+    pub spsr: u64,
+    /// q0 through q31
+    pub fp_regs: [u128; 32],
+    pub fpsr: u64,
+    pub fpcr: u64,
+}
+
+const _: () = assert!(core::mem::size_of::<TrapFrame>() == 800);
+
+/// Which of the 16 vector table entries was taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExceptionKind {
+    pub source: ExceptionSource,
+    pub ty: ExceptionType,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionSource {
+    CurrentElSp0,
+    CurrentElSpx,
+    LowerEl64,
+    LowerEl32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionType {
+    Synchronous,
+    Irq,
+    Fiq,
+    SError,
+}
+
+impl ExceptionKind {
+    fn from_vector(v: u64) -> Self {
+        let source = match v / 4 {
+            0 => ExceptionSource::CurrentElSp0,
+            1 => ExceptionSource::CurrentElSpx,
+            2 => ExceptionSource::LowerEl64,
+            _ => ExceptionSource::LowerEl32,
+        };
+        let ty = match v % 4 {
+            0 => ExceptionType::Synchronous,
+            1 => ExceptionType::Irq,
+            2 => ExceptionType::Fiq,
+            _ => ExceptionType::SError,
+        };
+        ExceptionKind { source, ty }
+    }
+}
+
+/// Decoded Data/Instruction Fault Status Code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultStatus {
+    AddressSize { level: u8 },
+    Translation { level: u8 },
+    AccessFlag { level: u8 },
+    Permission { level: u8 },
+    SyncExternal,
+    Alignment,
+    Other(u8),
+}
+
+impl From<u32> for FaultStatus {
+    fn from(fsc: u32) -> Self {
+        let level = (fsc & 0b11) as u8;
+        match fsc & 0b11_1111 {
+            0b00_0000..=0b00_0011 => FaultStatus::AddressSize { level },
+            0b00_0100..=0b00_0111 => FaultStatus::Translation { level },
+            0b00_1000..=0b00_1011 => FaultStatus::AccessFlag { level },
+            0b00_1100..=0b00_1111 => FaultStatus::Permission { level },
+            0b01_0000 => FaultStatus::SyncExternal,
+            0b10_0001 => FaultStatus::Alignment,
+            v => FaultStatus::Other(v as u8),
+        }
+    }
+}
+
+/// The exception class from ESR_EL1, with the interesting parts of the ISS pulled out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExceptionClass {
+    /// Unknown reason, which is what executing an undefined instruction reports.
+    Undefined,
+    TrappedWfx,
+    SimdFpAccess,
+    IllegalExecutionState,
+    Svc(u16),
+    TrappedSysReg,
+    InstructionAbort {
+        lower_el: bool,
+        fault: FaultStatus,
+    },
+    PcAlignment,
+    DataAbort {
+        lower_el: bool,
+        write: bool,
+        fault: FaultStatus,
+    },
+    SpAlignment,
+    SError,
+    Breakpoint,
+    SoftwareStep,
+    Watchpoint,
+    Brk(u16),
+    Other(u8),
+}
+
+impl From<u64> for ExceptionClass {
+    fn from(esr: u64) -> Self {
+        let ec = ((esr >> 26) & 0x3f) as u8;
+        let iss = (esr & 0x1ff_ffff) as u32;
+        match ec {
+            0x00 => ExceptionClass::Undefined,
+            0x01 => ExceptionClass::TrappedWfx,
+            0x07 => ExceptionClass::SimdFpAccess,
+            0x0e => ExceptionClass::IllegalExecutionState,
+            0x15 => ExceptionClass::Svc(iss as u16),
+            0x18 => ExceptionClass::TrappedSysReg,
+            0x20 | 0x21 => ExceptionClass::InstructionAbort {
+                lower_el: ec == 0x20,
+                fault: FaultStatus::from(iss),
+            },
+            0x22 => ExceptionClass::PcAlignment,
+            0x24 | 0x25 => ExceptionClass::DataAbort {
+                lower_el: ec == 0x24,
+                write: iss & (1 << 6) != 0,
+                fault: FaultStatus::from(iss),
+            },
+            0x26 => ExceptionClass::SpAlignment,
+            0x2f => ExceptionClass::SError,
+            0x30 | 0x31 => ExceptionClass::Breakpoint,
+            0x32 | 0x33 => ExceptionClass::SoftwareStep,
+            0x34 | 0x35 => ExceptionClass::Watchpoint,
+            0x3c => ExceptionClass::Brk(iss as u16),
+            v => ExceptionClass::Other(v),
+        }
+    }
+}
+
+/// Syndrome registers captured at the time of an exception.
+#[derive(Debug, Clone, Copy)]
+pub struct Syndrome {
+    pub esr: u64,
+    pub far: u64,
+    pub class: ExceptionClass,
+}
+
+impl Syndrome {
+    fn read() -> Self {
+        let esr: u64;
+        let far: u64;
+        unsafe {
+            asm!("mrs {}, esr_el1", out(reg) esr);
+            asm!("mrs {}, far_el1", out(reg) far);
+        }
+        Syndrome {
+            esr,
+            far,
+            class: ExceptionClass::from(esr),
+        }
+    }
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, chunk) in self.regs.chunks(4).enumerate() {
+            for (j, reg) in chunk.iter().enumerate() {
+                write!(f, "x{:<2} {:#018x}  ", i * 4 + j, reg)?;
+            }
+            writeln!(f)?;
+        }
+        // The interrupted SP_EL1 is just above the frame that was pushed onto it.
+        let sp_el1 = self as *const Self as usize + core::mem::size_of::<Self>();
+        writeln!(f, "sp_el0 {:#018x}  sp_el1 {:#018x}", self.sp_el0, sp_el1)?;
+        writeln!(f, "elr    {:#018x}  spsr   {:#018x}", self.elr, self.spsr)
+    }
+}
+
+/// Prints the state at a fatal exception straight to the console, which is about to panic.
+fn dump(kind: ExceptionKind, syndrome: &Syndrome, frame: &TrapFrame) {
+    // Set for good, as the panic which follows never returns.
+    static DUMPING: AtomicBool = AtomicBool::new(false);
+    let mut uart = match log::emergency_console() {
+        Some(uart) => uart,
+        None => return,
+    };
+    if DUMPING.swap(true, Ordering::Relaxed) {
+        let _ = writeln!(uart, "\nException while printing an exception");
+        return;
+    }
+    let _ = writeln!(
+        uart,
+        "\nException {:?} from {:?}: {:?}",
+        kind.ty, kind.source, syndrome.class
+    );
+    let _ = writeln!(
+        uart,
+        "esr    {:#018x}  far    {:#018x}",
+        syndrome.esr, syndrome.far
+    );
+    let _ = write!(uart, "{}", frame);
+    // Frame records are only walked on the kernel's own stacks.
+    let fp = match kind.source {
+        ExceptionSource::CurrentElSp0 | ExceptionSource::CurrentElSpx => frame.regs[29] as usize,
+        _ => 0,
+    };
+    let _ = backtrace::print(&mut uart, Some(frame.elr as usize), fp);
+}
+
+/// Unmasks IRQs on this cpu.
+#[inline]
+pub fn enable_interrupts() {
+    unsafe { asm!("msr daifclr, #2") };
+}
+
+/// Masks IRQs on this cpu.
+#[inline]
+pub fn disable_interrupts() {
+    unsafe { asm!("msr daifset, #2") };
+}
+
+/// Returns whether IRQs are currently unmasked on this cpu.
+#[inline]
+pub fn interrupts_enabled() -> bool {
+    let daif: u64;
+    unsafe { asm!("mrs {}, daif", out(reg) daif) };
+    daif & (1 << 7) == 0
+}
+
+/// Runs `f` with IRQs masked, restoring the previous mask afterwards.
+pub fn without_interrupts<R>(f: impl FnOnce() -> R) -> R {
+    let were_enabled = interrupts_enabled();
+    disable_interrupts();
+    let out = f();
+    if were_enabled {
+        enable_interrupts();
+    }
+    out
+}
+
+#[no_mangle]
+extern "C" fn handle_exception(vector: u64, frame: &mut TrapFrame) {
+    let kind = ExceptionKind::from_vector(vector);
+    match kind.ty {
+        ExceptionType::Synchronous => handle_sync(kind, frame),
+        ExceptionType::Irq => {
+            crate::gic::handle_irq();
+            // Only switch threads once the interrupt has been completed at the GIC.
+            crate::sched::preempt();
+        }
+        ExceptionType::Fiq | ExceptionType::SError => {
+            let syndrome = Syndrome::read();
+            dump(kind, &syndrome, frame);
+            panic!("Unhandled {:?}", kind.ty);
+        }
+    }
+}
+
+fn handle_sync(kind: ExceptionKind, frame: &mut TrapFrame) {
+    let syndrome = Syndrome::read();
+    if crate::gdb::trap(kind, syndrome.class, frame) {
+        return;
+    }
+    match syndrome.class {
+        ExceptionClass::Brk(imm) if kind.source != ExceptionSource::LowerEl64 => {
+            warn!("brk #{:#x} at {:#x}", imm, frame.elr);
+            // Unlike svc, ELR points at the brk itself so step over it.
+            frame.elr += 4;
+        }
+        ExceptionClass::Svc(0) if kind.source == ExceptionSource::LowerEl64 => {
+            crate::syscall::dispatch(frame)
+        }
+        ExceptionClass::Svc(imm) => {
+            // ELR already points past the svc, so returning resumes the caller.
+            warn!("Unhandled svc #{} at {:#x}", imm, frame.elr - 4);
+        }
+        class if kind.source == ExceptionSource::LowerEl64 => {
+            // A fault in a user process only takes down that process.
+            warn!(
+                "Killing process after {:?} at {:#x} (far {:#x})",
+                class, frame.elr, syndrome.far
+            );
+            crate::process::exit_current(-1);
+        }
+        class => {
+            dump(kind, &syndrome, frame);
+            match class {
+                ExceptionClass::DataAbort { .. } => panic!("Data abort at {:#x}", syndrome.far),
+                ExceptionClass::InstructionAbort { .. } => {
+                    panic!("Instruction abort at {:#x}", syndrome.far)
+                }
+                ExceptionClass::Undefined => panic!("Undefined instruction at {:#x}", frame.elr),
+                class => panic!("Unhandled synchronous exception {:?}", class),
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use crate::fs::{self, FileDescriptor, FileMode, SeekFrom};
+use crate::path::Path;
+use crate::process::KernelFs;
+use alloc::{string::String, vec::Vec};
+
+#[derive(Debug)]
+pub enum FileErr {
+    Open(fs::OpenErr),
+    Read(fs::ReadErr),
+    Write(fs::WriteErr),
+    Seek(fs::SeekErr),
+    Stat(fs::StatErr),
+    Unlink(fs::UnlinkErr),
+    IsDir(fs::IsDirErr),
+    AsDir(fs::AsDirErr),
+    Mkdir(fs::MkdirErr),
+    Rmdir(fs::RmdirErr),
+    /// The path names a directory where a file was expected.
+    IsDirectory,
+    /// The path names a file where a directory was expected.
+    NotDirectory,
+    /// The path has no last component to name a file by, like `/`.
+    NoFileName,
+}
+
+macro_rules! from_err {
+    ($($variant:ident($err:ty)),* $(,)?) => {
+        $(
+            impl From<$err> for FileErr {
+                fn from(e: $err) -> Self {
+                    FileErr::$variant(e)
+                }
+            }
+        )*
+    };
+}
+
+from_err!(
+    Open(fs::OpenErr),
+    Read(fs::ReadErr),
+    Write(fs::WriteErr),
+    Seek(fs::SeekErr),
+    Stat(fs::StatErr),
+    Unlink(fs::UnlinkErr),
+    IsDir(fs::IsDirErr),
+    AsDir(fs::AsDirErr),
+    Mkdir(fs::MkdirErr),
+    Rmdir(fs::RmdirErr),
+);
+
+impl FileErr {
+    /// Whether the error just means nothing exists at the path.
+    pub fn is_not_found(&self) -> bool {
+        // Opening a missing file without permission to create it is reported as the latter.
+        matches!(
+            self,
+            FileErr::Open(fs::OpenErr::PathDoesNotExist | fs::OpenErr::ModeCannotCreateFile)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Info {
+    pub size: u32,
+    pub is_dir: bool,
+}
+
+/// Opens the absolute `path`, starting from the `root` directory.
+pub fn open(
+    fs: &mut KernelFs,
+    root: FileDescriptor,
+    path: &Path,
+    mode: FileMode,
+) -> Result<FileDescriptor, fs::OpenErr> {
+    if path.is_root() {
+        // The file system needs at least one component, so name the root through itself.
+        fs.open(root, &["."], mode)
+    } else {
+        fs.open(root, &path.components(), mode)
+    }
+}
+
+/// Runs `f` on `path` opened with `mode`, closing it again afterwards.
+fn with_open<T>(
+    fs: &mut KernelFs,
+    root: FileDescriptor,
+    path: &Path,
+    mode: FileMode,
+    f: impl FnOnce(&mut KernelFs, FileDescriptor) -> Result<T, FileErr>,
+) -> Result<T, FileErr> {
+    let fd = open(fs, root, path, mode)?;
+    let out = f(fs, fd);
+    let _ = fs.close(fd);
+    out
+}
+
+pub fn stat(fs: &mut KernelFs, root: FileDescriptor, path: &Path) -> Result<Info, FileErr> {
+    with_open(fs, root, path, FileMode::MustExist, |fs, fd| {
+        Ok(Info {
+            size: fs.stat(fd)?.size,
+            is_dir: fs.is_directory(fd)?,
+        })
+    })
+}
+
+/// Names of everything in the directory at `path`, including `.` and `..`.
+pub fn list(fs: &mut KernelFs, root: FileDescriptor, path: &Path) -> Result<Vec<String>, FileErr> {
+    with_open(fs, root, path, FileMode::MustExist, |fs, fd| {
+        if !fs.is_directory(fd)? {
+            return Err(FileErr::NotDirectory);
+        }
+        let dir = fs.as_directory(fd)?;
+        let names = dir
+            .entries()
+            .map(|(name, _)| String::from_utf8_lossy(name).into_owned())
+            .collect();
+        Ok(names)
+    })
+}
+
+/// Makes an empty directory at `path`.
+pub fn mkdir(fs: &mut KernelFs, root: FileDescriptor, path: &Path) -> Result<(), FileErr> {
+    let name = path.file_name().ok_or(FileErr::NoFileName)?;
+    let parent = path.parent().ok_or(FileErr::NoFileName)?;
+    with_open(fs, root, &parent, FileMode::MustExist, |fs, dir| {
+        let made = fs.mkdir(dir, name)?;
+        let _ = fs.close(made);
+        Ok(())
+    })
+}
+
+/// Removes the empty directory at `path`.
+pub fn rmdir(fs: &mut KernelFs, root: FileDescriptor, path: &Path) -> Result<(), FileErr> {
+    if path.file_name().is_none() {
+        return Err(FileErr::NoFileName);
+    }
+    fs.rmdir(root, &path.components())?;
+    Ok(())
+}
+
+/// Reads all of the file at `path`.
+pub fn read(fs: &mut KernelFs, root: FileDescriptor, path: &Path) -> Result<Vec<u8>, FileErr> {
+    with_open(fs, root, path, FileMode::R, |fs, fd| {
+        // Directories can only be read whole, and not as bytes.
+        if fs.is_directory(fd)? {
+            return Err(FileErr::IsDirectory);
+        }
+        // Reads do not stop at the end of the file, so only ask for what is there.
+        let size = fs.stat(fd)?.size as usize;
+        let mut data = alloc::vec![0; size];
+        let mut done = 0;
+        while done < size {
+            match fs.read(fd, &mut data[done..])? {
+                0 => break,
+                n => done += n,
+            }
+        }
+        data.truncate(done);
+        Ok(data)
+    })
+}
+
+/// Writes `data` to the file at `path`, creating it if needed. Anything already in the file
+/// is replaced unless `append` is set.
+pub fn write(
+    fs: &mut KernelFs,
+    root: FileDescriptor,
+    path: &Path,
+    data: &[u8],
+    append: bool,
+) -> Result<(), FileErr> {
+    if path.file_name().is_none() {
+        return Err(FileErr::NoFileName);
+    }
+    let mode = if append {
+        FileMode::RW
+    } else {
+        // There is no truncation, so start over with a new file instead.
+        match remove(fs, root, path) {
+            Err(e) if !e.is_not_found() => return Err(e),
+            _ => {}
+        }
+        FileMode::New
+    };
+    with_open(fs, root, path, mode, |fs, fd| {
+        if fs.is_directory(fd)? {
+            return Err(FileErr::IsDirectory);
+        }
+        if append {
+            fs.seek(fd, SeekFrom::End(0))?;
+        }
+        let mut done = 0;
+        while done < data.len() {
+            done += fs.write(fd, &data[done..])?;
+        }
+        Ok(())
+    })
+}
+
+/// Creates an empty file at `path` unless something is already there.
+pub fn touch(fs: &mut KernelFs, root: FileDescriptor, path: &Path) -> Result<(), FileErr> {
+    with_open(fs, root, path, FileMode::RW, |_, _| Ok(()))
+}
+
+/// Removes the file at `path`. Directories are left to `rmdir`.
+pub fn remove(fs: &mut KernelFs, root: FileDescriptor, path: &Path) -> Result<(), FileErr> {
+    if stat(fs, root, path)?.is_dir {
+        return Err(FileErr::IsDirectory);
+    }
+    fs.unlink(root, &path.components())?;
+    Ok(())
+}
+
+/// Where copying `from` to `to` puts the file, which is inside `to` if it is a directory.
+fn destination(
+    fs: &mut KernelFs,
+    root: FileDescriptor,
+    from: &Path,
+    to: &Path,
+) -> Result<Path, FileErr> {
+    match stat(fs, root, to) {
+        Ok(Info { is_dir: true, .. }) => {
+            let name = from.file_name().ok_or(FileErr::NoFileName)?;
+            Ok(to.join(&Path::parse(name)))
+        }
+        Err(e) if !e.is_not_found() => Err(e),
+        _ => Ok(to.clone()),
+    }
+}
+
+/// Copies the file at `from` to `to`, replacing whatever file is there.
+pub fn copy(
+    fs: &mut KernelFs,
+    root: FileDescriptor,
+    from: &Path,
+    to: &Path,
+) -> Result<(), FileErr> {
+    let to = destination(fs, root, from, to)?;
+    if to == *from {
+        return Ok(());
+    }
+    let data = read(fs, root, from)?;
+    write(fs, root, &to, &data, false)
+}
+
+/// Moves the file at `from` to `to`. The file system has no rename, so this is a copy
+/// followed by removing the original.
+pub fn rename(
+    fs: &mut KernelFs,
+    root: FileDescriptor,
+    from: &Path,
+    to: &Path,
+) -> Result<(), FileErr> {
+    let to = destination(fs, root, from, to)?;
+    if to == *from {
+        return Ok(());
+    }
+    let data = read(fs, root, from)?;
+    write(fs, root, &to, &data, false)?;
+    remove(fs, root, from)
+}
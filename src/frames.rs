@@ -0,0 +1,223 @@
+use crate::device_tree::{DeviceTree, Node};
+use crate::exceptions::without_interrupts;
+use crate::mmu::{self, page_down, page_up, PAGE_SIZE};
+
+/// Bookkeeping for every 4KiB frame between the lowest and highest `/memory` addresses. A set
+/// bit means the frame is in use, which includes holes between memory regions.
+pub struct FrameAllocator {
+    /// Physical address of frame 0.
+    base: usize,
+    num_frames: usize,
+    bitmap: &'static mut [u64],
+    /// Frames which are backed by memory.
+    total: usize,
+    free: usize,
+    /// Frames which were withheld at boot: the kernel, the device tree and reservations.
+    reserved: usize,
+    /// Where to start looking for a free frame.
+    hint: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameStats {
+    pub total: usize,
+    pub free: usize,
+    pub reserved: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameInitErr {
+    NoMemory,
+    /// There was no room after the kernel image for the allocation bitmap.
+    NoRoomForBitmap,
+}
+
+impl FrameAllocator {
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / 64] & (1 << (frame % 64)) != 0
+    }
+
+    fn set(&mut self, frame: usize, used: bool) {
+        if self.is_used(frame) == used {
+            return;
+        }
+        if used {
+            self.bitmap[frame / 64] |= 1 << (frame % 64);
+            self.free -= 1;
+        } else {
+            self.bitmap[frame / 64] &= !(1 << (frame % 64));
+            self.free += 1;
+        }
+    }
+
+    /// Marks every frame overlapping `[start, end)` as used.
+    fn reserve(&mut self, start: usize, end: usize) {
+        let first = page_down(start).saturating_sub(self.base) / PAGE_SIZE;
+        let last = (page_up(end).saturating_sub(self.base) / PAGE_SIZE).min(self.num_frames);
+        for frame in first..last {
+            self.set(frame, true);
+        }
+    }
+
+    /// Marks every frame entirely inside `[start, end)` as free.
+    fn release(&mut self, start: usize, end: usize) {
+        let first = page_up(start).saturating_sub(self.base) / PAGE_SIZE;
+        let last = (page_down(end).saturating_sub(self.base) / PAGE_SIZE).min(self.num_frames);
+        for frame in first..last {
+            self.set(frame, false);
+        }
+    }
+
+    fn addr_of(&self, frame: usize) -> usize {
+        self.base + frame * PAGE_SIZE
+    }
+
+    pub fn alloc(&mut self) -> Option<usize> {
+        let words = self.bitmap.len();
+        let word = (0..words)
+            .map(|i| (self.hint / 64 + i) % words)
+            .find(|&w| self.bitmap[w] != !0)?;
+        let frame = word * 64 + self.bitmap[word].trailing_ones() as usize;
+        if frame >= self.num_frames {
+            return None;
+        }
+        self.set(frame, true);
+        self.hint = frame;
+        Some(self.addr_of(frame))
+    }
+
+    /// Allocates `n` physically contiguous frames, returning the address of the first.
+    pub fn alloc_contiguous(&mut self, n: usize) -> Option<usize> {
+        let mut run = 0;
+        for frame in 0..self.num_frames {
+            if self.is_used(frame) {
+                run = 0;
+                continue;
+            }
+            run += 1;
+            if run == n {
+                let first = frame + 1 - n;
+                for f in first..=frame {
+                    self.set(f, true);
+                }
+                return Some(self.addr_of(first));
+            }
+        }
+        None
+    }
+
+    /// Returns `n` frames starting at `addr` to the allocator.
+    pub fn free(&mut self, addr: usize, n: usize) {
+        assert_eq!(addr % PAGE_SIZE, 0, "Freeing unaligned frame {:#x}", addr);
+        let first = (addr - self.base) / PAGE_SIZE;
+        for frame in first..first + n {
+            assert!(self.is_used(frame), "Double free of frame {:#x}", addr);
+            self.set(frame, false);
+        }
+        self.hint = self.hint.min(first);
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            total: self.total,
+            free: self.free,
+            reserved: self.reserved,
+        }
+    }
+}
+
+static mut FRAMES: Option<FrameAllocator> = None;
+
+/// Builds the frame allocator from `/memory`, then withholds the kernel image, the device tree,
+/// the FDT memory reservation block and the children of `/reserved-memory`.
+///
+/// The allocation bitmap is placed directly after the kernel image.
+pub fn init(dtb: &DeviceTree, root: &Node) -> Result<FrameStats, FrameInitErr> {
+    let (low, high) = mmu::memory_regions(root).fold((usize::MAX, 0), |(low, high), (s, l)| {
+        (low.min(s), high.max(s + l))
+    });
+    if low >= high {
+        return Err(FrameInitErr::NoMemory);
+    }
+    let base = page_down(low);
+    let num_frames = (page_down(high) - base) / PAGE_SIZE;
+    let words = (num_frames + 63) / 64;
+
+    let (kernel_start, _, _, kernel_end) = mmu::kernel_image();
+    let bitmap_start = page_up(kernel_end);
+    let bitmap_end = bitmap_start + words * core::mem::size_of::<u64>();
+    if !mmu::memory_regions(root).any(|(s, l)| s <= bitmap_start && bitmap_end <= s + l) {
+        return Err(FrameInitErr::NoRoomForBitmap);
+    }
+    let bitmap = unsafe { core::slice::from_raw_parts_mut(bitmap_start as *mut u64, words) };
+    bitmap.fill(!0);
+
+    let mut frames = FrameAllocator {
+        base,
+        num_frames,
+        bitmap,
+        total: 0,
+        free: 0,
+        reserved: 0,
+        hint: 0,
+    };
+    for (start, size) in mmu::memory_regions(root) {
+        frames.release(start, start + size);
+    }
+    frames.total = frames.free;
+
+    frames.reserve(kernel_start, bitmap_end);
+    let dtb_start = dtb as *const DeviceTree as usize;
+    frames.reserve(dtb_start, dtb_start + dtb.total_size());
+    for (addr, size) in dtb.memory_reservations() {
+        frames.reserve(addr as usize, (addr + size) as usize);
+    }
+    if let Some(reserved) = root.child_by_name("reserved-memory") {
+        let address_cells = reserved.address_cells();
+        let size_cells = reserved.size_cells();
+        // Children with only a `size` are allocated dynamically by their users.
+        for reg in reserved
+            .children()
+            .filter_map(|child| child.prop_by_name("reg"))
+        {
+            for (addr, size) in reg.reg(address_cells, size_cells) {
+                frames.reserve(addr, addr + size);
+            }
+        }
+    }
+    frames.reserved = frames.total - frames.free;
+
+    let stats = frames.stats();
+    unsafe { FRAMES = Some(frames) };
+    Ok(stats)
+}
+
+fn with<R>(f: impl FnOnce(&mut FrameAllocator) -> R) -> Option<R> {
+    without_interrupts(|| unsafe { FRAMES.as_mut().map(f) })
+}
+
+/// Allocates a single frame, returning its physical address.
+pub fn alloc() -> Option<usize> {
+    with(|frames| frames.alloc()).flatten()
+}
+
+/// Allocates a single frame and fills it with zeroes.
+pub fn alloc_zeroed() -> Option<usize> {
+    let addr = alloc()?;
+    unsafe { core::ptr::write_bytes(addr as *mut u8, 0, PAGE_SIZE) };
+    Some(addr)
+}
+
+/// Allocates `n` physically contiguous frames, returning the address of the first.
+pub fn alloc_contiguous(n: usize) -> Option<usize> {
+    with(|frames| frames.alloc_contiguous(n)).flatten()
+}
+
+/// Frees `n` frames starting at `addr`, which must have come from this allocator.
+pub fn free(addr: usize, n: usize) {
+    with(|frames| frames.free(addr, n));
+}
+
+pub fn stats() -> FrameStats {
+    with(|frames| frames.stats()).unwrap_or_default()
+}
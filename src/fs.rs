@@ -70,7 +70,7 @@ pub struct FileDescEntry {
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
 pub struct FileStat {
-    size: u32,
+    pub size: u32,
 }
 
 /// Number of entries inside of a directory
@@ -646,10 +646,7 @@ where
         } else {
             let mut buf = [0u8; 2 * B::BLOCK_SIZE];
             self.gbi
-                .read(self.inode_md, block, &mut buf[..B::BLOCK_SIZE])
-                .map_err(|_| LoadINodeErr::RWErr)?;
-            self.gbi
-                .read(self.inode_md, block + 1, &mut buf[B::BLOCK_SIZE..])
+                .read_many(self.inode_md, block, &mut buf)
                 .map_err(|_| LoadINodeErr::RWErr)?;
             Ok(INode::from_slice(
                 &buf[offset..offset + core::mem::size_of::<INode>()],
@@ -693,17 +690,11 @@ where
         } else {
             let mut buf = [0u8; 2 * B::BLOCK_SIZE];
             self.gbi
-                .read(self.inode_md, block, &mut buf[..B::BLOCK_SIZE])
-                .map_err(|_| SaveINodeErr::ReadFailed)?;
-            self.gbi
-                .read(self.inode_md, block + 1, &mut buf[B::BLOCK_SIZE..])
+                .read_many(self.inode_md, block, &mut buf)
                 .map_err(|_| SaveINodeErr::ReadFailed)?;
             inode.to_slice(&mut buf[offset..offset + core::mem::size_of::<INode>()]);
             self.gbi
-                .write(self.inode_md, block, &buf[..B::BLOCK_SIZE])
-                .map_err(|_| SaveINodeErr::WriteFailed)?;
-            self.gbi
-                .write(self.inode_md, block + 1, &buf[B::BLOCK_SIZE..])
+                .write_many(self.inode_md, block, &buf)
                 .map_err(|_| SaveINodeErr::WriteFailed)?;
         }
         Ok(())
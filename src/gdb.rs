@@ -0,0 +1,578 @@
+use crate::exceptions::{ExceptionClass, ExceptionKind, ExceptionSource, TrapFrame};
+use crate::mmu::{self, PAGE_SIZE};
+use crate::spinlock::SpinLock;
+use crate::uart::UART;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// The `brk` immediate of breakpoints set by the debugger and of `breakpoint()`.
+pub const GDB_BRK: u16 = 0xdb;
+/// Encoding of `brk #0`.
+const BRK: u32 = 0xd420_0000;
+const MAX_BREAKPOINTS: usize = 32;
+/// Largest packet either side may send, which gdb learns from `qSupported`.
+const PACKET_SIZE: usize = 4096;
+/// Number of registers in a `g` packet: x0-x30, sp, pc and cpsr.
+const NUM_REGS: usize = 34;
+
+const MDSCR_SS: u64 = 1 << 0;
+/// Lets debug exceptions be taken from EL1 itself.
+const MDSCR_KDE: u64 = 1 << 13;
+const SPSR_SS: u64 = 1 << 21;
+const SPSR_D: u64 = 1 << 9;
+const SPSR_I: u64 = 1 << 7;
+const SPSR_MODE_MASK: u64 = 0xf;
+const SPSR_EL1H: u64 = 0b0101;
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+const SIGTRAP: u8 = 5;
+/// Errors are reported as errno values.
+const EFAULT: u8 = 14;
+const EINVAL: u8 = 22;
+
+#[derive(Debug, Clone, Copy)]
+struct Breakpoint {
+    addr: usize,
+    /// The instruction the `brk` replaced.
+    insn: u32,
+}
+
+/// A packet being built to send back.
+struct Reply {
+    buf: [u8; PACKET_SIZE],
+    len: usize,
+}
+
+impl Reply {
+    fn push(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(PACKET_SIZE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+    }
+
+    fn push_hex(&mut self, byte: u8) {
+        self.push(&[
+            HEX_DIGITS[(byte >> 4) as usize],
+            HEX_DIGITS[(byte & 0xf) as usize],
+        ]);
+    }
+
+    /// Pushes the low `size` bytes of `v` in target, so little endian, order.
+    fn push_le(&mut self, v: u64, size: usize) {
+        for i in 0..size {
+            self.push_hex((v >> (8 * i)) as u8);
+        }
+    }
+
+    fn ok(&mut self) {
+        self.push(b"OK");
+    }
+
+    fn error(&mut self, errno: u8) {
+        self.push(b"E");
+        self.push_hex(errno);
+    }
+}
+
+/// What to do once a packet has been handled.
+enum Action {
+    Stay,
+    Resume {
+        step: bool,
+    },
+    /// Resume once the reply is sent.
+    Detach,
+}
+
+struct Stub {
+    /// Base of the PL011 the debugger talks to.
+    port: usize,
+    breakpoints: [Option<Breakpoint>; MAX_BREAKPOINTS],
+    /// While single stepping, whether IRQs were masked in the stepped code.
+    stepping: Option<bool>,
+    packet: [u8; PACKET_SIZE],
+    reply: Reply,
+}
+
+// Kept out of the heap, which may be what broke when we stop after a panic.
+static STUB: SpinLock<Option<Stub>> = SpinLock::new(None);
+/// Set once `STUB` is, so that checking for it never waits on a lock a panic may have left held.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Starts the stub on `port`, which must not be used for anything else.
+pub fn init(port: UART) {
+    *STUB.lock() = Some(Stub {
+        port: port.base() as usize,
+        breakpoints: [None; MAX_BREAKPOINTS],
+        stepping: None,
+        packet: [0; PACKET_SIZE],
+        reply: Reply {
+            buf: [0; PACKET_SIZE],
+            len: 0,
+        },
+    });
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Whether there is a port for a debugger to attach to.
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Stops in the stub until the debugger continues.
+#[inline(always)]
+pub fn breakpoint() {
+    // The immediate is `GDB_BRK`.
+    unsafe { asm!("brk #0xdb") };
+}
+
+/// Handles the `brk` and single step exceptions which belong to the stub, returning false for
+/// anything else. Only the kernel is debugged, so a `brk` from a process is left to kill it.
+pub fn trap(kind: ExceptionKind, class: ExceptionClass, frame: &mut TrapFrame) -> bool {
+    let from_kernel = matches!(
+        kind.source,
+        ExceptionSource::CurrentElSp0 | ExceptionSource::CurrentElSpx
+    );
+    if !from_kernel && matches!(class, ExceptionClass::Brk(_)) {
+        return false;
+    }
+    let mut stub = STUB.lock();
+    let stub = match stub.as_mut() {
+        Some(stub) => stub,
+        None => return false,
+    };
+    let skip_brk = match class {
+        // A `brk` compiled into the kernel rather than one of ours has to be stepped over.
+        ExceptionClass::Brk(GDB_BRK) => !stub.has_breakpoint(frame.elr as usize),
+        ExceptionClass::SoftwareStep if stub.stepping.is_some() => {
+            stub.end_step(frame);
+            false
+        }
+        _ => return false,
+    };
+    stub.run(frame, skip_brk);
+    true
+}
+
+impl Stub {
+    fn has_breakpoint(&self, addr: usize) -> bool {
+        self.breakpoints.iter().flatten().any(|bp| bp.addr == addr)
+    }
+
+    /// Talks to the debugger until it resumes execution.
+    fn run(&mut self, frame: &mut TrapFrame, mut skip_brk: bool) {
+        let mut port = unsafe { UART::new(self.port as _) };
+        self.reply.len = 0;
+        self.reply.push(b"S");
+        self.reply.push_hex(SIGTRAP);
+        send(&mut port, &self.reply);
+        loop {
+            let len = receive(&mut port, &mut self.packet);
+            self.reply.len = 0;
+            let action = handle(
+                &self.packet[..len],
+                frame,
+                &mut self.breakpoints,
+                &mut self.reply,
+            );
+            match action {
+                Action::Stay => send(&mut port, &self.reply),
+                Action::Detach => {
+                    send(&mut port, &self.reply);
+                    if skip_brk {
+                        frame.elr += 4;
+                    }
+                    return;
+                }
+                Action::Resume { step } => {
+                    let resume_at = &self.packet[1..len];
+                    if let Some(addr) = parse_hex(resume_at) {
+                        frame.elr = addr;
+                        skip_brk = false;
+                    }
+                    if skip_brk {
+                        frame.elr += 4;
+                    }
+                    if step {
+                        self.start_step(frame);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn start_step(&mut self, frame: &mut TrapFrame) {
+        self.stepping = Some(frame.spsr & SPSR_I != 0);
+        // Step with IRQs masked so that the next stop is not inside of an interrupt handler,
+        // and with debug exceptions unmasked so that the step is taken at all.
+        frame.spsr = (frame.spsr | SPSR_SS | SPSR_I) & !SPSR_D;
+        unsafe {
+            let mdscr: u64;
+            asm!("mrs {}, mdscr_el1", out(reg) mdscr);
+            asm!(
+                "msr oslar_el1, xzr",
+                "msr mdscr_el1, {}",
+                "isb",
+                in(reg) mdscr | MDSCR_SS | MDSCR_KDE,
+            );
+        }
+    }
+
+    fn end_step(&mut self, frame: &mut TrapFrame) {
+        if self.stepping.take() == Some(false) {
+            frame.spsr &= !SPSR_I;
+        }
+        unsafe {
+            let mdscr: u64;
+            asm!("mrs {}, mdscr_el1", out(reg) mdscr);
+            asm!("msr mdscr_el1, {}", "isb", in(reg) mdscr & !(MDSCR_SS | MDSCR_KDE));
+        }
+    }
+}
+
+/// Handles one packet, leaving the answer in `reply`.
+fn handle(
+    packet: &[u8],
+    frame: &mut TrapFrame,
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+    reply: &mut Reply,
+) -> Action {
+    let (&cmd, args) = match packet.split_first() {
+        Some(split) => split,
+        None => return Action::Stay,
+    };
+    match cmd {
+        b'?' => {
+            reply.push(b"S");
+            reply.push_hex(SIGTRAP);
+        }
+        b'g' => {
+            for n in 0..NUM_REGS {
+                let (v, size) = read_register(frame, n);
+                reply.push_le(v, size);
+            }
+        }
+        b'G' => {
+            let mut rest = args;
+            for n in 0..NUM_REGS {
+                let size = read_register(frame, n).1;
+                match rest.get(..2 * size).and_then(parse_le) {
+                    Some(v) => {
+                        // SP_EL1 cannot be moved from under the frame, so that one is skipped.
+                        write_register(frame, n, v);
+                    }
+                    None => break,
+                }
+                rest = &rest[2 * size..];
+            }
+            reply.ok();
+        }
+        b'p' => match parse_hex(args).map(|n| n as usize) {
+            Some(n) if n < NUM_REGS => {
+                let (v, size) = read_register(frame, n);
+                reply.push_le(v, size);
+            }
+            _ => reply.error(EINVAL),
+        },
+        b'P' => {
+            let written = split(args, b'=').and_then(|(n, v)| {
+                let n = parse_hex(n)? as usize;
+                Some(n < NUM_REGS && write_register(frame, n, parse_le(v)?))
+            });
+            match written {
+                Some(true) => reply.ok(),
+                _ => reply.error(EINVAL),
+            }
+        }
+        b'm' => match split(args, b',')
+            .and_then(|(addr, len)| Some((parse_hex(addr)? as usize, parse_hex(len)? as usize)))
+        {
+            Some((addr, len)) => {
+                // Each byte takes two characters.
+                let len = len.min(PACKET_SIZE / 2);
+                if !read_memory(addr, len, reply) {
+                    reply.len = 0;
+                    reply.error(EFAULT);
+                }
+            }
+            None => reply.error(EINVAL),
+        },
+        b'M' => {
+            let parsed = split(args, b':').and_then(|(range, data)| {
+                let (addr, len) = split(range, b',')?;
+                Some((parse_hex(addr)? as usize, parse_hex(len)? as usize, data))
+            });
+            match parsed {
+                Some((addr, len, data)) if data.len() == 2 * len => {
+                    let mut bytes = [0; PACKET_SIZE / 2];
+                    for (i, pair) in data.chunks(2).enumerate() {
+                        bytes[i] = parse_hex(pair).unwrap_or(0) as u8;
+                    }
+                    if write_memory(addr, &bytes[..len]) {
+                        reply.ok();
+                    } else {
+                        reply.error(EFAULT);
+                    }
+                }
+                _ => reply.error(EINVAL),
+            }
+        }
+        b'Z' | b'z' => {
+            // Only software breakpoints, `Z0,addr,kind`, are supported.
+            let addr = args
+                .strip_prefix(b"0,")
+                .and_then(|rest| split(rest, b','))
+                .and_then(|(addr, _)| parse_hex(addr))
+                .map(|addr| addr as usize);
+            match addr {
+                Some(addr) if cmd == b'Z' => match insert_breakpoint(breakpoints, addr) {
+                    Ok(()) => reply.ok(),
+                    Err(errno) => reply.error(errno),
+                },
+                Some(addr) => match remove_breakpoint(breakpoints, addr) {
+                    Ok(()) => reply.ok(),
+                    Err(errno) => reply.error(errno),
+                },
+                // An empty reply tells gdb the kind is unsupported.
+                None => {}
+            }
+        }
+        b'c' => return Action::Resume { step: false },
+        b's' => return Action::Resume { step: true },
+        b'D' | b'k' => {
+            for bp in breakpoints.iter().flatten() {
+                write_memory(bp.addr, &bp.insn.to_le_bytes());
+            }
+            *breakpoints = [None; MAX_BREAKPOINTS];
+            // Detaching expects an answer before the target runs again, killing does not.
+            if cmd == b'D' {
+                reply.ok();
+                return Action::Detach;
+            }
+            return Action::Resume { step: false };
+        }
+        // There is a single thread as far as gdb knows, so selecting one always works.
+        b'H' => reply.ok(),
+        b'q' if args.starts_with(b"Supported") => reply.push(b"PacketSize=1000"),
+        b'q' if args == b"Attached" => reply.push(b"1"),
+        // An empty reply for anything else means it is not supported.
+        _ => {}
+    }
+    Action::Stay
+}
+
+fn insert_breakpoint(
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+    addr: usize,
+) -> Result<(), u8> {
+    if addr % 4 != 0 {
+        return Err(EINVAL);
+    }
+    if breakpoints.iter().flatten().any(|bp| bp.addr == addr) {
+        return Ok(());
+    }
+    let slot = breakpoints
+        .iter_mut()
+        .find(|bp| bp.is_none())
+        .ok_or(EINVAL)?;
+    if !mmu::accessible(addr, false) {
+        return Err(EFAULT);
+    }
+    let insn = unsafe { (addr as *const u32).read_volatile() };
+    let brk = BRK | (GDB_BRK as u32) << 5;
+    if !write_memory(addr, &brk.to_le_bytes()) {
+        return Err(EFAULT);
+    }
+    *slot = Some(Breakpoint { addr, insn });
+    Ok(())
+}
+
+fn remove_breakpoint(
+    breakpoints: &mut [Option<Breakpoint>; MAX_BREAKPOINTS],
+    addr: usize,
+) -> Result<(), u8> {
+    let slot = breakpoints
+        .iter_mut()
+        .find(|bp| bp.map_or(false, |bp| bp.addr == addr))
+        .ok_or(EINVAL)?;
+    let insn = slot.take().unwrap().insn;
+    if !write_memory(addr, &insn.to_le_bytes()) {
+        return Err(EFAULT);
+    }
+    Ok(())
+}
+
+/// The value of register `n` in gdb's numbering, and its size in bytes.
+fn read_register(frame: &TrapFrame, n: usize) -> (u64, usize) {
+    match n {
+        0..=30 => (frame.regs[n], 8),
+        31 if frame.spsr & SPSR_MODE_MASK == SPSR_EL1H => {
+            // The interrupted SP_EL1 is just above the frame that was pushed onto it.
+            let sp = frame as *const TrapFrame as usize + core::mem::size_of::<TrapFrame>();
+            (sp as u64, 8)
+        }
+        31 => (frame.sp_el0, 8),
+        32 => (frame.elr, 8),
+        33 => (frame.spsr, 4),
+        _ => (0, 0),
+    }
+}
+
+fn write_register(frame: &mut TrapFrame, n: usize, v: u64) -> bool {
+    match n {
+        0..=30 => frame.regs[n] = v,
+        31 if frame.spsr & SPSR_MODE_MASK == SPSR_EL1H => return false,
+        31 => frame.sp_el0 = v,
+        32 => frame.elr = v,
+        // Only the flags, switching modes from under the debugger would not end well.
+        33 => frame.spsr = (frame.spsr & !0xf000_0000) | (v & 0xf000_0000),
+        _ => return false,
+    }
+    true
+}
+
+/// Appends `len` bytes at `addr` to `reply` as hex, failing if any of them are not mapped.
+fn read_memory(addr: usize, len: usize, reply: &mut Reply) -> bool {
+    let end = match addr.checked_add(len) {
+        Some(end) => end,
+        None => return false,
+    };
+    if !(mmu::page_down(addr)..end)
+        .step_by(PAGE_SIZE)
+        .all(|page| mmu::accessible(page, false))
+    {
+        return false;
+    }
+    for a in addr..end {
+        reply.push_hex(unsafe { (a as *const u8).read_volatile() });
+    }
+    true
+}
+
+/// Writes `data` to `addr`, making read only kernel pages writable for the moment so that
+/// breakpoints can go into text.
+fn write_memory(addr: usize, data: &[u8]) -> bool {
+    let mut done = 0;
+    while done < data.len() {
+        let at = addr + done;
+        let n = (PAGE_SIZE - at % PAGE_SIZE).min(data.len() - done);
+        if !mmu::accessible(at, false) {
+            return false;
+        }
+        let unlocked = !mmu::accessible(at, true);
+        // Made read only again as soon as the write is done.
+        let unlock =
+            || mmu::kernel_space().map_or(false, |space| unsafe { space.set_writable(at, true) });
+        if unlocked && !unlock() {
+            return false;
+        }
+        for (i, b) in data[done..done + n].iter().enumerate() {
+            unsafe { ((at + i) as *mut u8).write_volatile(*b) };
+        }
+        if unlocked {
+            mmu::kernel_space().map(|space| unsafe { space.set_writable(at, false) });
+        }
+        sync_icache(at, n);
+        done += n;
+    }
+    true
+}
+
+/// Makes instructions written to `[addr, addr + len)` visible to instruction fetches.
+fn sync_icache(addr: usize, len: usize) {
+    let ctr: u64;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr) };
+    let iline = 4 << (ctr & 0xf);
+    let end = addr + len;
+    mmu::clean_dcache(addr, len);
+    unsafe {
+        for line in (addr & !(iline - 1)..end).step_by(iline) {
+            asm!("ic ivau, {}", in(reg) line);
+        }
+        asm!("dsb ish", "isb");
+    }
+}
+
+fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() > 16 {
+        return None;
+    }
+    s.iter()
+        .try_fold(0u64, |acc, c| Some(acc << 4 | hex_digit(*c)? as u64))
+}
+
+/// Parses a register value, which gdb sends as bytes in target order.
+fn parse_le(s: &[u8]) -> Option<u64> {
+    if s.is_empty() || s.len() % 2 != 0 || s.len() > 16 {
+        return None;
+    }
+    s.chunks(2).enumerate().try_fold(0u64, |acc, (i, pair)| {
+        Some(acc | parse_hex(pair)? << (8 * i))
+    })
+}
+
+fn split(s: &[u8], sep: u8) -> Option<(&[u8], &[u8])> {
+    let i = s.iter().position(|c| *c == sep)?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+/// Waits for a packet with a good checksum, acknowledging it, and returns its length.
+fn receive(port: &mut UART, buf: &mut [u8; PACKET_SIZE]) -> usize {
+    'packet: loop {
+        while port.poll_read_byte() != b'$' {}
+        let mut len = 0;
+        let mut sum = 0u8;
+        loop {
+            match port.poll_read_byte() {
+                b'#' => break,
+                // The debugger started over.
+                b'$' => continue 'packet,
+                c => {
+                    if len < buf.len() {
+                        buf[len] = c;
+                        len += 1;
+                    }
+                    sum = sum.wrapping_add(c);
+                }
+            }
+        }
+        let checksum = [port.poll_read_byte(), port.poll_read_byte()];
+        if parse_hex(&checksum) == Some(sum as u64) {
+            port.poll_write_byte(b'+');
+            return len;
+        }
+        port.poll_write_byte(b'-');
+    }
+}
+
+/// Sends `reply` until the debugger acknowledges it.
+fn send(port: &mut UART, reply: &Reply) {
+    let data = &reply.buf[..reply.len];
+    let sum = data.iter().fold(0u8, |sum, c| sum.wrapping_add(*c));
+    loop {
+        port.poll_write_byte(b'$');
+        for c in data {
+            port.poll_write_byte(*c);
+        }
+        port.poll_write_byte(b'#');
+        port.poll_write_byte(HEX_DIGITS[(sum >> 4) as usize]);
+        port.poll_write_byte(HEX_DIGITS[(sum & 0xf) as usize]);
+        match port.poll_read_byte() {
+            b'+' => return,
+            // Anything but a retransmit request, like a stray ^C, counts as received.
+            b'-' => continue,
+            _ => return,
+        }
+    }
+}
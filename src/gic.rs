@@ -0,0 +1,352 @@
+use crate::device_tree::Node;
+use crate::utils::mb;
+use core::arch::asm;
+use core::ptr::{read_volatile, write_volatile};
+
+/// Number of interrupt IDs the GIC architecture allows, IDs 1020-1023 are special.
+pub const MAX_IRQS: usize = 1020;
+
+/// First INTID of each interrupt type.
+const PPI_BASE: u32 = 16;
+const SPI_BASE: u32 = 32;
+
+// Distributor registers shared by v2 and v3.
+const GICD_CTLR: usize = 0x000;
+const GICD_TYPER: usize = 0x004;
+const GICD_IGROUPR: usize = 0x080;
+const GICD_ISENABLER: usize = 0x100;
+const GICD_ICENABLER: usize = 0x180;
+const GICD_IPRIORITYR: usize = 0x400;
+const GICD_ITARGETSR: usize = 0x800;
+const GICD_ICFGR: usize = 0xc00;
+const GICD_IROUTER: usize = 0x6000;
+
+// v2 CPU interface registers.
+const GICC_CTLR: usize = 0x00;
+const GICC_PMR: usize = 0x04;
+const GICC_IAR: usize = 0x0c;
+const GICC_EOIR: usize = 0x10;
+
+// v3 redistributor registers.
+const GICR_TYPER: usize = 0x08;
+const GICR_WAKER: usize = 0x14;
+const GICR_FRAME_SIZE: usize = 0x20000;
+const GICR_SGI_OFFSET: usize = 0x10000;
+
+const DEFAULT_PRIORITY: u8 = 0xa0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Trigger {
+    Level,
+    Edge,
+}
+
+/// An interrupt as described by a device tree `interrupts` property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqSpec {
+    /// The GIC INTID of this interrupt.
+    pub irq: u32,
+    pub trigger: Trigger,
+}
+
+impl IrqSpec {
+    /// Decodes the three cell GIC binding: `<type number flags>`.
+    pub fn from_cells(ty: u32, num: u32, flags: u32) -> Self {
+        let irq = match ty {
+            1 => PPI_BASE + num,
+            _ => SPI_BASE + num,
+        };
+        let trigger = if flags & 0b11 != 0 {
+            Trigger::Edge
+        } else {
+            Trigger::Level
+        };
+        IrqSpec { irq, trigger }
+    }
+}
+
+/// Returns the interrupts listed in a node's `interrupts` property.
+pub fn irqs_of<'a>(node: &Node<'a>) -> impl Iterator<Item = IrqSpec> + 'a {
+    let cells = node.prop_by_name("interrupts").map_or(&[][..], |p| p.value);
+    cells.chunks_exact(12).map(|c| {
+        let cell = |i: usize| u32::from_be_bytes([c[i], c[i + 1], c[i + 2], c[i + 3]]);
+        IrqSpec::from_cells(cell(0), cell(4), cell(8))
+    })
+}
+
+/// A function invoked with the INTID that fired and the data it was registered with.
+pub type IrqHandler = fn(irq: u32, data: usize);
+
+#[derive(Debug)]
+pub enum Gic {
+    V2 {
+        dist: *mut u8,
+        cpu: *mut u8,
+    },
+    V3 {
+        dist: *mut u8,
+        /// The redistributor frame of the boot cpu.
+        redist: *mut u8,
+    },
+}
+
+static mut GIC: Option<Gic> = None;
+static mut HANDLERS: [Option<(IrqHandler, usize)>; MAX_IRQS] = [None; MAX_IRQS];
+/// The interrupt the GIC itself signals maintenance events on.
+static mut MAINTENANCE_IRQ: Option<IrqSpec> = None;
+
+unsafe fn read_reg(base: *mut u8, offset: usize) -> u32 {
+    read_volatile(base.add(offset) as *const u32)
+}
+
+unsafe fn write_reg(base: *mut u8, offset: usize, v: u32) {
+    write_volatile(base.add(offset) as *mut u32, v)
+}
+
+/// Sets the bit for `irq` in a bank of one-bit-per-interrupt registers.
+unsafe fn set_bit(base: *mut u8, bank: usize, irq: u32) {
+    write_reg(base, bank + (irq as usize / 32) * 4, 1 << (irq % 32));
+}
+
+impl Gic {
+    /// Finds the interrupt controller under `root`, reading its `reg` and `interrupts`.
+    pub fn probe(root: &Node) -> Option<Self> {
+        let address_cells = root.address_cells();
+        let size_cells = root.size_cells();
+        let node = root.children().find(|child| {
+            child.is_compatible("arm,cortex-a15-gic") || child.is_compatible("arm,gic-v3")
+        })?;
+        let mut regs = node
+            .prop_by_name("reg")?
+            .reg(address_cells, size_cells)
+            .map(|(addr, _)| addr as *mut u8);
+        let dist = regs.next()?;
+        let second = regs.next()?;
+        unsafe {
+            MAINTENANCE_IRQ = irqs_of(&node).next();
+        }
+        if node.is_compatible("arm,gic-v3") {
+            let redist = unsafe { Self::redistributor_for(second, current_affinity())? };
+            Some(Gic::V3 { dist, redist })
+        } else {
+            Some(Gic::V2 { dist, cpu: second })
+        }
+    }
+
+    /// Walks the redistributor frames looking for the one which serves `affinity`.
+    unsafe fn redistributor_for(mut frame: *mut u8, affinity: u32) -> Option<*mut u8> {
+        loop {
+            let typer = read_volatile(frame.add(GICR_TYPER) as *const u64);
+            if (typer >> 32) as u32 == affinity {
+                return Some(frame);
+            }
+            // Last bit
+            if typer & (1 << 4) != 0 {
+                return None;
+            }
+            frame = frame.add(GICR_FRAME_SIZE);
+        }
+    }
+
+    fn dist(&self) -> *mut u8 {
+        match *self {
+            Gic::V2 { dist, .. } | Gic::V3 { dist, .. } => dist,
+        }
+    }
+
+    /// Number of interrupt lines the distributor implements.
+    pub fn num_irqs(&self) -> usize {
+        let typer = unsafe { read_reg(self.dist(), GICD_TYPER) };
+        (((typer & 0x1f) as usize + 1) * 32).min(MAX_IRQS)
+    }
+
+    /// The register bank which holds per-cpu configuration of `irq`.
+    fn bank_for(&self, irq: u32) -> *mut u8 {
+        match *self {
+            Gic::V3 { redist, .. } if irq < SPI_BASE => unsafe { redist.add(GICR_SGI_OFFSET) },
+            _ => self.dist(),
+        }
+    }
+
+    unsafe fn init(&self) {
+        let dist = self.dist();
+        let num_irqs = self.num_irqs() as u32;
+        write_reg(dist, GICD_CTLR, 0);
+        // Start with every shared interrupt masked.
+        for irq in (SPI_BASE..num_irqs).step_by(32) {
+            write_reg(dist, GICD_ICENABLER + (irq as usize / 32) * 4, !0);
+        }
+        match *self {
+            Gic::V2 { cpu, .. } => {
+                write_reg(dist, GICD_CTLR, 1);
+                write_reg(cpu, GICC_PMR, 0xff);
+                write_reg(cpu, GICC_CTLR, 1);
+            }
+            Gic::V3 { redist, .. } => {
+                for irq in (SPI_BASE..num_irqs).step_by(32) {
+                    write_reg(dist, GICD_IGROUPR + (irq as usize / 32) * 4, !0);
+                }
+                // ARE | EnableGrp1 | EnableGrp0
+                write_reg(dist, GICD_CTLR, (1 << 4) | (1 << 1) | 1);
+                // Wait for the write to propagate (RWP)
+                while read_reg(dist, GICD_CTLR) & (1 << 31) != 0 {}
+
+                // Wake up this cpu's redistributor.
+                let waker = read_reg(redist, GICR_WAKER);
+                write_reg(redist, GICR_WAKER, waker & !(1 << 1));
+                while read_reg(redist, GICR_WAKER) & (1 << 2) != 0 {}
+                let sgi = redist.add(GICR_SGI_OFFSET);
+                write_reg(sgi, GICD_ICENABLER, !0);
+                write_reg(sgi, GICD_IGROUPR, !0);
+
+                // Enable the system register interface, unmask all priorities, enable group 1.
+                let mut sre: u64;
+                asm!("mrs {}, s3_0_c12_c12_5", out(reg) sre);
+                sre |= 1;
+                asm!("msr s3_0_c12_c12_5, {}", "isb", in(reg) sre);
+                asm!("msr s3_0_c4_c6_0, {}", in(reg) 0xffu64);
+                asm!("msr s3_0_c12_c12_7, {}", "isb", in(reg) 1u64);
+            }
+        }
+        mb();
+    }
+
+    /// Sets the trigger mode, priority and routing of `spec` and unmasks it.
+    pub fn enable(&self, spec: IrqSpec) {
+        let irq = spec.irq as usize;
+        let bank = self.bank_for(spec.irq);
+        unsafe {
+            write_volatile(bank.add(GICD_IPRIORITYR + irq), DEFAULT_PRIORITY);
+            // SGIs have a fixed trigger mode
+            if spec.irq >= PPI_BASE {
+                let cfg = GICD_ICFGR + (irq / 16) * 4;
+                let shift = (irq % 16) * 2 + 1;
+                let v = read_reg(bank, cfg) & !(1 << shift);
+                let edge = (spec.trigger == Trigger::Edge) as u32;
+                write_reg(bank, cfg, v | edge << shift);
+            }
+            if spec.irq >= SPI_BASE {
+                match *self {
+                    Gic::V2 { dist, .. } => write_volatile(dist.add(GICD_ITARGETSR + irq), 1),
+                    Gic::V3 { dist, .. } => write_volatile(
+                        dist.add(GICD_IROUTER + irq * 8) as *mut u64,
+                        current_route(),
+                    ),
+                }
+            }
+            mb();
+            set_bit(bank, GICD_ISENABLER, spec.irq);
+            mb();
+        }
+    }
+
+    /// Masks `irq` at the distributor (or redistributor for private interrupts).
+    pub fn disable(&self, irq: u32) {
+        unsafe {
+            set_bit(self.bank_for(irq), GICD_ICENABLER, irq);
+            mb();
+        }
+    }
+
+    /// Acknowledges the highest priority pending interrupt, returning the raw IAR value.
+    fn ack(&self) -> u32 {
+        match *self {
+            Gic::V2 { cpu, .. } => unsafe { read_reg(cpu, GICC_IAR) },
+            Gic::V3 { .. } => {
+                let iar: u64;
+                unsafe { asm!("mrs {}, s3_0_c12_c12_0", out(reg) iar) };
+                iar as u32
+            }
+        }
+    }
+
+    fn eoi(&self, iar: u32) {
+        match *self {
+            Gic::V2 { cpu, .. } => unsafe { write_reg(cpu, GICC_EOIR, iar) },
+            Gic::V3 { .. } => unsafe { asm!("msr s3_0_c12_c12_1, {}", in(reg) iar as u64) },
+        }
+    }
+}
+
+/// The affinity fields of MPIDR_EL1 packed the way GICR_TYPER expects.
+fn current_affinity() -> u32 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr) };
+    ((mpidr & 0xff_ffff) | ((mpidr >> 8) & 0xff00_0000)) as u32
+}
+
+/// A GICD_IROUTER value targeting this cpu. Aff3 lives in bits 39:32 there, as bit 31 selects
+/// 1-of-N routing, which must stay clear.
+fn current_route() -> u64 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr) };
+    (mpidr & 0xff_ffff) | (((mpidr >> 32) & 0xff) << 32)
+}
+
+/// Discovers and initializes the interrupt controller. Interrupts still need to be unmasked on
+/// the cpu with `exceptions::enable_interrupts`.
+pub fn init(root: &Node) -> Option<&'static Gic> {
+    let gic = Gic::probe(root)?;
+    unsafe {
+        gic.init();
+        GIC = Some(gic);
+        GIC.as_ref()
+    }
+}
+
+pub fn get() -> Option<&'static Gic> {
+    unsafe { GIC.as_ref() }
+}
+
+/// Returns the interrupt the controller raises for its own maintenance events, if any.
+pub fn maintenance_irq() -> Option<IrqSpec> {
+    unsafe { MAINTENANCE_IRQ }
+}
+
+/// Registers `handler` to be called with `data` whenever `spec` fires, and unmasks it.
+pub fn register_handler(spec: IrqSpec, handler: IrqHandler, data: usize) -> Result<(), ()> {
+    let gic = get().ok_or(())?;
+    let slot = unsafe { HANDLERS.get_mut(spec.irq as usize).ok_or(())? };
+    crate::exceptions::without_interrupts(|| *slot = Some((handler, data)));
+    gic.enable(spec);
+    Ok(())
+}
+
+/// Masks `irq` and forgets its handler.
+pub fn unregister_handler(irq: u32) {
+    if let Some(gic) = get() {
+        gic.disable(irq);
+    }
+    if let Some(slot) = unsafe { HANDLERS.get_mut(irq as usize) } {
+        crate::exceptions::without_interrupts(|| *slot = None);
+    }
+}
+
+/// Dispatches every pending interrupt to its handler. Called from the IRQ vector.
+pub(crate) fn handle_irq() {
+    let gic = if let Some(gic) = get() {
+        gic
+    } else {
+        return;
+    };
+    loop {
+        let iar = gic.ack();
+        let irq = iar & 0x3ff;
+        // 1020-1023 are spurious or reserved, meaning nothing more is pending.
+        if irq as usize >= MAX_IRQS {
+            break;
+        }
+        match unsafe { HANDLERS[irq as usize] } {
+            Some((handler, data)) => handler(irq, data),
+            // Nobody wants it, so stop it from firing again.
+            None => gic.disable(irq),
+        }
+        gic.eoi(iar);
+    }
+}
+
+/// Sleeps the cpu until the next interrupt arrives.
+#[inline]
+pub fn wait_for_interrupt() {
+    unsafe { asm!("wfi") };
+}
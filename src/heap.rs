@@ -0,0 +1,185 @@
+use crate::exceptions::without_interrupts;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr::{self, null_mut};
+
+/// Every block, free or allocated, is a multiple of this and aligned to it. It is also exactly
+/// large enough to hold the header of a free block.
+const MIN_BLOCK: usize = core::mem::size_of::<FreeBlock>();
+
+/// Header written at the start of every free region, which are kept sorted by address.
+#[repr(C, align(16))]
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
+}
+
+const fn align_up(v: usize, align: usize) -> usize {
+    (v + align - 1) & !(align - 1)
+}
+
+/// A first-fit allocator over an address ordered free list, which coalesces neighbouring
+/// blocks on free.
+pub struct LinkedListHeap {
+    head: *mut FreeBlock,
+    total: usize,
+    used: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct HeapStats {
+    pub total: usize,
+    pub used: usize,
+}
+
+impl LinkedListHeap {
+    pub const fn empty() -> Self {
+        LinkedListHeap {
+            head: null_mut(),
+            total: 0,
+            used: 0,
+        }
+    }
+
+    /// Hands `[start, start + size)` to the heap.
+    pub unsafe fn add_region(&mut self, start: usize, size: usize) {
+        let aligned = align_up(start, MIN_BLOCK);
+        let end = (start + size) & !(MIN_BLOCK - 1);
+        if aligned >= end {
+            return;
+        }
+        self.total += end - aligned;
+        self.insert(aligned, end - aligned);
+    }
+
+    /// The size and alignment a request actually occupies.
+    fn block_layout(layout: Layout) -> (usize, usize) {
+        let size = align_up(layout.size().max(MIN_BLOCK), MIN_BLOCK);
+        (size, layout.align().max(MIN_BLOCK))
+    }
+
+    pub unsafe fn alloc(&mut self, layout: Layout) -> *mut u8 {
+        let (size, align) = Self::block_layout(layout);
+        let mut prev: *mut *mut FreeBlock = &mut self.head;
+        while !(*prev).is_null() {
+            let block = *prev;
+            let block_start = block as usize;
+            let block_end = block_start + (*block).size;
+            // Because everything is a multiple of MIN_BLOCK, padding in front or behind the
+            // allocation is always either empty or large enough to be a free block itself.
+            let start = align_up(block_start, align);
+            let end = start + size;
+            if end > block_end {
+                prev = &mut (*block).next;
+                continue;
+            }
+            let next = (*block).next;
+            *prev = next;
+            if end < block_end {
+                let rest = end as *mut FreeBlock;
+                rest.write(FreeBlock {
+                    size: block_end - end,
+                    next,
+                });
+                *prev = rest;
+            }
+            if start > block_start {
+                (*block).size = start - block_start;
+                (*block).next = *prev;
+                *prev = block;
+            }
+            self.used += size;
+            return start as *mut u8;
+        }
+        null_mut()
+    }
+
+    pub unsafe fn dealloc(&mut self, ptr: *mut u8, layout: Layout) {
+        let (size, _) = Self::block_layout(layout);
+        self.used -= size;
+        self.insert(ptr as usize, size);
+    }
+
+    /// Inserts a free block in address order, merging it with its neighbours.
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        let mut prev: *mut FreeBlock = null_mut();
+        let mut curr = self.head;
+        while !curr.is_null() && (curr as usize) < addr {
+            prev = curr;
+            curr = (*curr).next;
+        }
+        let block = addr as *mut FreeBlock;
+        block.write(FreeBlock { size, next: curr });
+        if !curr.is_null() && addr + size == curr as usize {
+            (*block).size += (*curr).size;
+            (*block).next = (*curr).next;
+        }
+        if prev.is_null() {
+            self.head = block;
+        } else if prev as usize + (*prev).size == addr {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+        } else {
+            (*prev).next = block;
+        }
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            total: self.total,
+            used: self.used,
+        }
+    }
+}
+
+/// The kernel heap, which masks interrupts while it is being modified.
+pub struct KernelHeap(UnsafeCell<LinkedListHeap>);
+
+unsafe impl Sync for KernelHeap {}
+
+impl KernelHeap {
+    fn with<R>(&self, f: impl FnOnce(&mut LinkedListHeap) -> R) -> R {
+        without_interrupts(|| f(unsafe { &mut *self.0.get() }))
+    }
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with(|heap| heap.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.with(|heap| heap.dealloc(ptr, layout))
+    }
+
+    unsafe fn realloc(&self, old: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        // Blocks are rounded up, so small changes may still fit in the same one.
+        if LinkedListHeap::block_layout(new_layout) == LinkedListHeap::block_layout(layout) {
+            return old;
+        }
+        let new = self.alloc(new_layout);
+        if !new.is_null() {
+            ptr::copy_nonoverlapping(old, new, layout.size().min(new_size));
+            self.dealloc(old, layout);
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static HEAP: KernelHeap = KernelHeap(UnsafeCell::new(LinkedListHeap::empty()));
+
+#[alloc_error_handler]
+fn alloc_error(layout: Layout) -> ! {
+    panic!("Kernel heap exhausted allocating {:?}", layout);
+}
+
+/// Gives the kernel heap `[start, end)` to allocate from.
+pub unsafe fn init(start: usize, end: usize) {
+    HEAP.with(|heap| heap.add_region(start, end.saturating_sub(start)));
+}
+
+pub fn stats() -> HeapStats {
+    HEAP.with(|heap| heap.stats())
+}
@@ -1,40 +1,109 @@
-use crate::{block_interface::BlockDevice, virtio::VirtIOBlk};
+use crate::{
+    block_interface::BlockDevice,
+    virtio::{BlkErr, BlkToken, VirtIOBlk, SECTOR_SIZE},
+};
+use alloc::collections::VecDeque;
+
+/// Waits for requests until at most `keep` are left in flight, failing if any of them did.
+fn settle(dev: &mut VirtIOBlk, in_flight: &mut VecDeque<BlkToken>, keep: usize) -> Result<(), ()> {
+    let mut result = Ok(());
+    while in_flight.len() > keep {
+        let token = in_flight.pop_front().unwrap();
+        if dev.wait(token).is_err() {
+            result = Err(());
+        }
+    }
+    result
+}
+
+/// Keeps trying `submit` until there is room in the queue for it, waiting on the oldest request
+/// in flight to make some.
+fn submit_all(
+    dev: &mut VirtIOBlk,
+    in_flight: &mut VecDeque<BlkToken>,
+    mut submit: impl FnMut(&mut VirtIOBlk) -> Result<BlkToken, BlkErr>,
+) -> Result<(), ()> {
+    let mut result = Ok(());
+    loop {
+        match submit(dev) {
+            Ok(token) => {
+                in_flight.push_back(token);
+                return result;
+            }
+            Err(BlkErr::Busy) if !in_flight.is_empty() => {
+                let keep = in_flight.len() - 1;
+                result = result.and(settle(dev, in_flight, keep));
+            }
+            Err(_) => return Err(()),
+        }
+    }
+}
 
 impl BlockDevice for VirtIOBlk<'_> {
     // This is a size I randomly picked when allocating the image file.
     const NUM_BLOCKS: usize = 2048;
 
-    const BLOCK_SIZE: usize = 512;
+    const BLOCK_SIZE: usize = SECTOR_SIZE;
     fn read(&mut self, start_sector_num: u32, dst: &mut [u8]) -> Result<usize, ()> {
-        let mut arr_chunk = dst.array_chunks_mut::<512>();
-        let mut i = 0;
-        for chunk in arr_chunk.by_ref() {
-            self.read(start_sector_num as u64 + i, chunk);
-            i += 1;
-        }
-        let rem = arr_chunk.into_remainder();
-        if !rem.is_empty() {
-            let mut buf = [0u8; 512];
-            self.read(start_sector_num as u64 + i, &mut buf);
-            rem.copy_from_slice(&buf[..rem.len()]);
-        }
-        Ok(dst.len())
+        self.read_many(&mut [(start_sector_num, dst)])
     }
     fn write(&mut self, start_sector_num: u32, src: &[u8]) -> Result<usize, ()> {
-        let mut arr_chunk = src.array_chunks::<512>();
-        let mut i = 0;
-        for chunk in arr_chunk.by_ref() {
-            self.write(start_sector_num as u64 + i, chunk);
-            i += 1;
+        self.write_many(&[(start_sector_num, src)])
+    }
+    fn read_many(&mut self, reqs: &mut [(u32, &mut [u8])]) -> Result<usize, ()> {
+        let mut in_flight = VecDeque::new();
+        let mut result = Ok(());
+        for (sector, dst) in reqs.iter_mut() {
+            let whole = dst.len() - dst.len() % SECTOR_SIZE;
+            let (dst, rem) = dst.split_at_mut(whole);
+            if !dst.is_empty() {
+                // Waited for before returning, so `dst` outlives the request.
+                let submitted = submit_all(self, &mut in_flight, |dev| unsafe {
+                    dev.submit_read(*sector as u64, dst)
+                });
+                result = result.and(submitted);
+            }
+            if !rem.is_empty() {
+                let mut buf = [0u8; SECTOR_SIZE];
+                let last = *sector as u64 + (whole / SECTOR_SIZE) as u64;
+                // The synchronous read needs room in the queue, which requests in flight may be
+                // taking up.
+                result = result.and(settle(self, &mut in_flight, 0));
+                result = result.and(VirtIOBlk::read(self, last, &mut buf).map_err(|_| ()));
+                rem.copy_from_slice(&buf[..rem.len()]);
+            }
         }
-        let rem = arr_chunk.remainder();
-        if !rem.is_empty() {
-            let mut buf = [0u8; 512];
-            // read in what's already there, overwrite the beginning and overwrite the end.
-            self.read(start_sector_num as u64 + i, &mut buf);
-            buf[..rem.len()].copy_from_slice(rem);
-            self.write(start_sector_num as u64 + i, &mut buf);
+        result.and(settle(self, &mut in_flight, 0))?;
+        Ok(reqs.iter().map(|(_, dst)| dst.len()).sum())
+    }
+    fn write_many(&mut self, reqs: &[(u32, &[u8])]) -> Result<usize, ()> {
+        let mut in_flight = VecDeque::new();
+        let mut result = Ok(());
+        for &(sector, src) in reqs {
+            let whole = src.len() - src.len() % SECTOR_SIZE;
+            let (src, rem) = src.split_at(whole);
+            if !src.is_empty() {
+                // Waited for before returning, so `src` outlives the request.
+                let submitted = submit_all(self, &mut in_flight, |dev| unsafe {
+                    dev.submit_write(sector as u64, src)
+                });
+                result = result.and(submitted);
+            }
+            if !rem.is_empty() {
+                let mut buf = [0u8; SECTOR_SIZE];
+                let last = sector as u64 + (whole / SECTOR_SIZE) as u64;
+                // Earlier writes in flight may cover the same sector, so they have to land before
+                // it is read back.
+                result = result.and(settle(self, &mut in_flight, 0));
+                // read in what's already there, overwrite the beginning and overwrite the end.
+                let rmw = VirtIOBlk::read(self, last, &mut buf).and_then(|()| {
+                    buf[..rem.len()].copy_from_slice(rem);
+                    VirtIOBlk::write(self, last, &buf)
+                });
+                result = result.and(rmw.map_err(|_| ()));
+            }
         }
-        Ok(src.len())
+        result.and(settle(self, &mut in_flight, 0))?;
+        Ok(reqs.iter().map(|(_, src)| src.len()).sum())
     }
 }
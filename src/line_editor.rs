@@ -0,0 +1,236 @@
+use crate::uart::UART;
+use alloc::{collections::VecDeque, string::String, vec::Vec};
+use core::fmt::Write;
+
+/// Number of lines kept for up/down recall.
+const HISTORY_LEN: usize = 64;
+
+const CTRL_A: u8 = 0x01;
+const CTRL_C: u8 = 0x03;
+const CTRL_E: u8 = 0x05;
+const BACKSPACE: u8 = 0x08;
+const TAB: u8 = 0x09;
+const CTRL_K: u8 = 0x0b;
+const CTRL_U: u8 = 0x15;
+const CTRL_W: u8 = 0x17;
+const ESC: u8 = 0x1b;
+const DEL: u8 = 0x7f;
+
+/// Which word tab completion is being asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionKind {
+    /// The first word on the line.
+    Command,
+    /// Any later word.
+    Argument,
+}
+
+/// Reads lines from a terminal with cursor movement, emacs style editing keys, history and
+/// tab completion.
+#[derive(Default)]
+pub struct LineEditor {
+    history: VecDeque<Vec<u8>>,
+}
+
+/// The line being edited.
+struct State<'a> {
+    prompt: &'a str,
+    line: Vec<u8>,
+    cursor: usize,
+}
+
+impl State<'_> {
+    /// Redraws the whole line and puts the terminal cursor back where ours is.
+    fn refresh(&self, uart: &mut UART) {
+        uart.write_byte(b'\r');
+        uart.write_bytes(self.prompt.as_bytes());
+        uart.write_bytes(&self.line);
+        // Clear whatever is left over from a longer line.
+        uart.write_bytes(b"\x1b[K");
+        let back = self.line.len() - self.cursor;
+        if back > 0 {
+            let _ = write!(uart, "\x1b[{}D", back);
+        }
+    }
+
+    fn insert(&mut self, bytes: &[u8]) {
+        let tail = self.line.split_off(self.cursor);
+        self.line.extend_from_slice(bytes);
+        self.line.extend_from_slice(&tail);
+        self.cursor += bytes.len();
+    }
+
+    fn replace(&mut self, line: Vec<u8>) {
+        self.cursor = line.len();
+        self.line = line;
+    }
+
+    /// Start of the word the cursor is in or just after.
+    fn word_start(&self) -> usize {
+        let before = &self.line[..self.cursor];
+        let end = before.iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1);
+        before[..end]
+            .iter()
+            .rposition(|c| *c == b' ')
+            .map_or(0, |i| i + 1)
+    }
+}
+
+impl LineEditor {
+    pub fn new() -> Self {
+        LineEditor {
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Shows `prompt` and reads a line. `complete` is given the partial word under the cursor
+    /// and returns every candidate it could be completed to.
+    pub fn read_line(
+        &mut self,
+        uart: &mut UART,
+        prompt: &str,
+        complete: &mut dyn FnMut(&str, CompletionKind) -> Vec<String>,
+    ) -> Vec<u8> {
+        let mut state = State {
+            prompt,
+            line: Vec::new(),
+            cursor: 0,
+        };
+        // `history.len()` is the line being typed, lower indices are older entries.
+        let mut recall = self.history.len();
+        let mut scratch = Vec::new();
+        uart.write_bytes(prompt.as_bytes());
+        loop {
+            match uart.read_byte() {
+                b'\r' | b'\n' => break,
+                CTRL_A => state.cursor = 0,
+                CTRL_E => state.cursor = state.line.len(),
+                CTRL_C => {
+                    // Abandon the line, keeping what was typed on screen.
+                    state.replace(Vec::new());
+                    uart.write_bytes(b"^C\n");
+                    uart.write_bytes(prompt.as_bytes());
+                    continue;
+                }
+                CTRL_K => state.line.truncate(state.cursor),
+                CTRL_U => {
+                    state.line.drain(..state.cursor);
+                    state.cursor = 0;
+                }
+                CTRL_W => {
+                    let start = state.word_start();
+                    state.line.drain(start..state.cursor);
+                    state.cursor = start;
+                }
+                BACKSPACE | DEL => {
+                    if state.cursor > 0 {
+                        state.cursor -= 1;
+                        state.line.remove(state.cursor);
+                    }
+                }
+                TAB => self.complete(uart, &mut state, complete),
+                ESC => {
+                    if uart.read_byte() != b'[' {
+                        continue;
+                    }
+                    match uart.read_byte() {
+                        // Up
+                        b'A' if recall > 0 => {
+                            if recall == self.history.len() {
+                                scratch = core::mem::take(&mut state.line);
+                            }
+                            recall -= 1;
+                            state.replace(self.history[recall].clone());
+                        }
+                        // Down
+                        b'B' if recall < self.history.len() => {
+                            recall += 1;
+                            let line = if recall == self.history.len() {
+                                core::mem::take(&mut scratch)
+                            } else {
+                                self.history[recall].clone()
+                            };
+                            state.replace(line);
+                        }
+                        // Right
+                        b'C' if state.cursor < state.line.len() => state.cursor += 1,
+                        // Left
+                        b'D' if state.cursor > 0 => state.cursor -= 1,
+                        b'H' => state.cursor = 0,
+                        b'F' => state.cursor = state.line.len(),
+                        // Delete, sent as `ESC [ 3 ~`
+                        b'3' => {
+                            if uart.read_byte() == b'~' && state.cursor < state.line.len() {
+                                state.line.remove(state.cursor);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                v if v.is_ascii_graphic() || v == b' ' => state.insert(&[v]),
+                _ => continue,
+            }
+            state.refresh(uart);
+        }
+        uart.write_byte(b'\n');
+
+        let line = state.line;
+        if !line.iter().all(|c| *c == b' ') && self.history.back() != Some(&line) {
+            if self.history.len() == HISTORY_LEN {
+                self.history.pop_front();
+            }
+            self.history.push_back(line.clone());
+        }
+        line
+    }
+
+    fn complete(
+        &mut self,
+        uart: &mut UART,
+        state: &mut State,
+        complete: &mut dyn FnMut(&str, CompletionKind) -> Vec<String>,
+    ) {
+        let start = state.word_start();
+        let kind = if state.line[..start].iter().all(|c| *c == b' ') {
+            CompletionKind::Command
+        } else {
+            CompletionKind::Argument
+        };
+        let word = match core::str::from_utf8(&state.line[start..state.cursor]) {
+            Ok(word) => word,
+            Err(_) => return,
+        };
+        let candidates: Vec<String> = complete(word, kind)
+            .into_iter()
+            .filter(|c| c.starts_with(word))
+            .collect();
+        match candidates.as_slice() {
+            [] => {}
+            [only] => {
+                let rest = &only.as_bytes()[word.len()..];
+                state.insert(rest);
+                state.insert(b" ");
+            }
+            many => {
+                let common = many[1..].iter().fold(many[0].len(), |len, c| {
+                    many[0]
+                        .bytes()
+                        .zip(c.bytes())
+                        .take(len)
+                        .take_while(|(a, b)| a == b)
+                        .count()
+                });
+                if common > word.len() {
+                    state.insert(&many[0].as_bytes()[word.len()..common]);
+                } else {
+                    // Nothing more to fill in, so show the options below the line.
+                    uart.write_byte(b'\n');
+                    for candidate in many {
+                        let _ = write!(uart, "{}  ", candidate);
+                    }
+                    uart.write_byte(b'\n');
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use crate::spinlock::SpinLock;
+use crate::timer;
+use crate::uart::{PolledWriter, UART};
+use alloc::{string::String, vec::Vec};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+
+/// Bytes of log kept for `dmesg`, older lines are overwritten.
+const DMESG_SIZE: usize = 16 * 1024;
+/// Longest line a single message is formatted to, anything past it is cut off.
+const MAX_LINE: usize = 256;
+/// Prefix of every `module_path!()` in this crate, which filters leave out.
+const CRATE_PREFIX: &str = "aarch64os::";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    fn from_u8(v: u8) -> Option<Self> {
+        let level = match v {
+            1 => Level::Error,
+            2 => Level::Warn,
+            3 => Level::Info,
+            4 => Level::Debug,
+            5 => Level::Trace,
+            _ => return None,
+        };
+        Some(level)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// The most verbose level let through, `None` turning logging off.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LevelFilter(pub Option<Level>);
+
+impl LevelFilter {
+    fn from_u8(v: u8) -> Self {
+        LevelFilter(Level::from_u8(v))
+    }
+
+    fn as_u8(&self) -> u8 {
+        self.0.map_or(0, |level| level as u8)
+    }
+
+    fn allows(&self, level: Level) -> bool {
+        self.0.map_or(false, |max| level <= max)
+    }
+}
+
+impl core::str::FromStr for LevelFilter {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let level = match s {
+            "off" => None,
+            "error" => Some(Level::Error),
+            "warn" => Some(Level::Warn),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => return Err(()),
+        };
+        Ok(LevelFilter(level))
+    }
+}
+
+impl fmt::Display for LevelFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.map_or("off", |level| level.name()))
+    }
+}
+
+/// A byte ring which drops the oldest bytes when full.
+struct Ring {
+    buf: [u8; DMESG_SIZE],
+    start: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Ring {
+            buf: [0; DMESG_SIZE],
+            start: 0,
+            len: 0,
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            let end = (self.start + self.len) % DMESG_SIZE;
+            self.buf[end] = b;
+            if self.len == DMESG_SIZE {
+                self.start = (self.start + 1) % DMESG_SIZE;
+            } else {
+                self.len += 1;
+            }
+        }
+    }
+
+    fn contents(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.len);
+        let end = self.start + self.len;
+        if end <= DMESG_SIZE {
+            out.extend_from_slice(&self.buf[self.start..end]);
+        } else {
+            out.extend_from_slice(&self.buf[self.start..]);
+            out.extend_from_slice(&self.buf[..end - DMESG_SIZE]);
+        }
+        // Don't start on the tail of a line which was partly overwritten.
+        if self.len == DMESG_SIZE {
+            if let Some(i) = out.iter().position(|c| *c == b'\n') {
+                out.drain(..=i);
+            }
+        }
+        out
+    }
+}
+
+/// A line being formatted on the stack, since logging may happen before there is a heap or
+/// from an interrupt handler.
+struct Line {
+    buf: [u8; MAX_LINE],
+    len: usize,
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(MAX_LINE - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+static CONSOLE: AtomicUsize = AtomicUsize::new(0);
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+/// Per module overrides of `MAX_LEVEL`, as module paths without the crate prefix.
+static FILTERS: SpinLock<Vec<(String, LevelFilter)>> = SpinLock::new(Vec::new());
+static DMESG: SpinLock<Ring> = SpinLock::new(Ring::new());
+
+/// Sends log output to `uart`, which is also where panics are reported.
+pub fn set_console(uart: &UART) {
+    CONSOLE.store(uart.base() as usize, Ordering::Relaxed);
+}
+
+/// The console found through `/chosen`, once there is one.
+pub fn console() -> Option<UART> {
+    match CONSOLE.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(unsafe { UART::new(base as _) }),
+    }
+}
+
+/// The console for panics and fatal exceptions, which never waits on a lock.
+pub fn emergency_console() -> Option<PolledWriter> {
+    console().map(UART::polled)
+}
+
+pub fn max_level() -> LevelFilter {
+    LevelFilter::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+/// Sets the level for modules without a filter of their own.
+pub fn set_max_level(filter: LevelFilter) {
+    MAX_LEVEL.store(filter.as_u8(), Ordering::Relaxed);
+}
+
+/// Sets the level for `module` and the modules inside of it, or removes the override when
+/// `filter` is `None`. `module` is a path like `virtio` or `sched`.
+pub fn set_module_level(module: &str, filter: Option<LevelFilter>) {
+    let mut filters = FILTERS.lock();
+    filters.retain(|(m, _)| m != module);
+    if let Some(filter) = filter {
+        filters.push((String::from(module), filter));
+    }
+}
+
+pub fn module_levels() -> Vec<(String, LevelFilter)> {
+    FILTERS.lock().clone()
+}
+
+fn short_module(module: &str) -> &str {
+    module.strip_prefix(CRATE_PREFIX).unwrap_or(module)
+}
+
+/// Whether a message at `level` from `module` would be logged.
+pub fn enabled(level: Level, module: &str) -> bool {
+    let module = short_module(module);
+    let filters = FILTERS.lock();
+    // The most specific filter wins.
+    let filter = filters
+        .iter()
+        .filter(|(m, _)| {
+            module == m || (module.starts_with(m.as_str()) && module[m.len()..].starts_with("::"))
+        })
+        .max_by_key(|(m, _)| m.len())
+        .map(|(_, filter)| *filter);
+    filter.unwrap_or_else(max_level).allows(level)
+}
+
+/// Records a message in the dmesg buffer and prints it on the console. Use the `error!`,
+/// `warn!`, `info!`, `debug!` and `trace!` macros rather than calling this.
+pub fn log(level: Level, module: &'static str, args: fmt::Arguments) {
+    if !enabled(level, module) {
+        return;
+    }
+    let mut line = Line {
+        buf: [0; MAX_LINE],
+        len: 0,
+    };
+    let t = timer::uptime();
+    let _ = write!(
+        line,
+        "[{:>5}.{:06}] {:<5} {}: ",
+        t.as_secs(),
+        t.subsec_micros(),
+        level.name(),
+        short_module(module)
+    );
+    let _ = line.write_fmt(args);
+    if line.len == MAX_LINE {
+        line.len -= 1;
+    }
+    line.buf[line.len] = b'\n';
+    line.len += 1;
+
+    // Holding the lock while printing keeps lines from different cores apart.
+    let mut dmesg = DMESG.lock();
+    dmesg.push(&line.buf[..line.len]);
+    if let Some(mut console) = console() {
+        console.write_bytes(&line.buf[..line.len]);
+    }
+}
+
+/// Everything still in the dmesg buffer.
+pub fn dmesg() -> Vec<u8> {
+    DMESG.lock().contents()
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)+) => {
+        $crate::log::log($level, module_path!(), format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Error, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Debug, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::log!($crate::log::Level::Trace, $($arg)+) };
+}
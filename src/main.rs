@@ -10,14 +10,39 @@
     specialization,
     const_maybe_uninit_uninit_array,
     const_maybe_uninit_write,
-    generic_associated_types
+    generic_associated_types,
+    alloc_error_handler
 )]
 #![allow(incomplete_features, unused)]
 
+extern crate alloc;
+
+pub mod backtrace;
 pub mod device_tree;
+pub mod elf;
+pub mod exceptions;
+pub mod files;
+pub mod frames;
+pub mod gdb;
+pub mod gic;
+pub mod heap;
+pub mod line_editor;
+pub mod log;
+pub mod mmu;
+pub mod path;
+pub mod process;
+pub mod psci;
+pub mod sched;
+pub mod script;
+pub mod shell;
+pub mod smp;
+pub mod spinlock;
+pub mod syscall;
+pub mod timer;
 pub mod uart;
 pub mod utils;
 pub mod virtio;
+pub mod virtqueue;
 
 pub mod array_vec;
 pub mod bit_array;
@@ -26,19 +51,19 @@ pub mod fs;
 
 pub mod impls;
 
+use alloc::boxed::Box;
 use block_interface::GlobalBlockInterface;
 use core::arch::global_asm;
 use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use device_tree::regs_to_usize;
 use virtio::{VirtIOBlkConfig, VirtIODevice, VirtIORegs};
 
 #[cfg(target_arch = "aarch64")]
 global_asm!(include_str!("boot.S"));
 
-use core::{
-    fmt::Write,
-    panic::PanicInfo,
-    str::{from_utf8, FromStr},
-};
+use core::{fmt::Write, panic::PanicInfo};
 
 fn null_terminated_str(bytes: &[u8]) -> &[u8] {
     if bytes[bytes.len() - 1] == 0 {
@@ -48,17 +73,6 @@ fn null_terminated_str(bytes: &[u8]) -> &[u8] {
     }
 }
 
-fn regs_to_usize(regs: &[u8], cell_size: usize) -> (usize, &[u8]) {
-    let mut result = 0;
-    let (work, rest) = regs.split_at(cell_size * 4);
-    for chunk in work.chunks(4) {
-        let mut c = [0; 4];
-        c.copy_from_slice(chunk);
-        result = result << 32 | (u32::from_be_bytes(c) as usize);
-    }
-    (result, rest)
-}
-
 #[no_mangle]
 pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree) {
     let mut uart = None;
@@ -80,18 +94,25 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree) {
             })
             .unwrap_or(2);
 
+        let mut stdout = None;
         if let Some(chosen) = root.child_by_name("chosen") {
             chosen
                 .prop_by_name("stdout-path")
                 .map(|stdout_path| null_terminated_str(stdout_path.value))
-                .filter(|stdout_path| stdout_path == b"/pl011@9000000")
+                // Anything after a ':' are the line settings, like `115200n8`.
                 .map(|stdout_path| {
-                    let stdout = root.child_by_path(stdout_path);
-                    if let Some(reg) = stdout.prop_by_name("reg") {
+                    let mut parts = stdout_path.splitn(2, |c| *c == b':');
+                    (parts.next().unwrap(), parts.next().unwrap_or(&[]))
+                })
+                .filter(|(stdout_path, _)| *stdout_path == b"/pl011@9000000")
+                .map(|(stdout_path, options)| {
+                    let node = root.child_by_path(stdout_path);
+                    if let Some(reg) = node.prop_by_name("reg") {
                         let (addr, rest) = regs_to_usize(reg.value, address_cell);
                         let (size, _) = regs_to_usize(rest, size_cell);
                         if size == 0x1000 {
                             uart = Some(unsafe { uart::UART::new(addr as _) });
+                            stdout = Some((node, options));
                         }
                     }
                 });
@@ -102,38 +123,146 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree) {
             return;
         };
 
-        let _ = write!(uart, "We booted!\n");
+        log::set_console(&uart);
+        backtrace::init(&root);
+        info!("We booted!");
+
+        if let Err(e) = mmu::init(&root) {
+            error!("Failed to enable the MMU: {:?}", e);
+        }
+
+        match frames::init(dtb, &root) {
+            Ok(stats) => {
+                info!(
+                    "Frames: {} free of {} ({} reserved)",
+                    stats.free, stats.total, stats.reserved
+                );
+            }
+            Err(e) => {
+                error!("Failed to initialize frame allocator: {:?}", e);
+            }
+        }
+
+        // The heap gets a quarter of the free frames, the rest are left for page granular users.
+        let heap_frames = frames::stats().free / 4;
+        if let Some(heap_start) = frames::alloc_contiguous(heap_frames) {
+            let heap_end = heap_start + heap_frames * mmu::PAGE_SIZE;
+            unsafe { heap::init(heap_start, heap_end) };
+            info!("Heap: {:#x}-{:#x}", heap_start, heap_end);
+        } else {
+            error!("Could not find memory for the heap");
+        }
+
+        match gic::init(&root) {
+            Some(gic) => {
+                info!("Interrupt controller: {:?}", gic);
+                exceptions::enable_interrupts();
+            }
+            None => {
+                warn!("No interrupt controller found");
+            }
+        }
+        if timer::init(&root, Duration::from_millis(10)).is_err() {
+            error!("Failed to start the generic timer");
+        }
+        if let Err(e) = sched::init() {
+            error!("Failed to start the scheduler: {:?}", e);
+        }
+        if let Some((node, options)) = stdout {
+            match uart::LineConfig::from_node(&root, &node, options) {
+                Some(config) => uart.configure(&config),
+                None => {
+                    warn!("No clock for the console, keeping its settings");
+                }
+            }
+            let irq = gic::irqs_of(&node).next();
+            if irq.map_or(true, |spec| uart.enable_interrupts(spec).is_err()) {
+                warn!("Console interrupts unavailable, polling instead");
+            }
+        }
+        // Another PL011 is left to the debugger.
+        let debug_port = root
+            .children_by_prop("compatible", |prop| prop.contains_str("arm,pl011"))
+            .filter(|node| {
+                node.prop_by_name("status")
+                    .map_or(true, |status| status.contains_str("okay"))
+            })
+            .find_map(|node| {
+                let reg = node.prop_by_name("reg")?;
+                let (addr, _) = regs_to_usize(reg.value, address_cell);
+                (addr != uart.base() as usize).then(|| (node, addr))
+            });
+        if let Some((node, addr)) = debug_port {
+            let mut port = unsafe { uart::UART::new(addr as _) };
+            if let Some(config) = uart::LineConfig::from_node(&root, &node, &[]) {
+                port.configure(&config);
+            }
+            gdb::init(port);
+            info!("GDB stub listening on the UART at {:#x}", addr);
+        }
+        match psci::init(&root) {
+            Some(conduit) => {
+                match psci::version() {
+                    Ok((major, minor)) => {
+                        info!("PSCI {}.{} via {:?}", major, minor, conduit);
+                    }
+                    Err(e) => {
+                        warn!("PSCI_VERSION failed: {:?}", e);
+                    }
+                }
+                let online = smp::init(&root);
+                info!("{} cpu(s) online", online);
+            }
+            None => {
+                warn!("No PSCI node, staying on one cpu");
+            }
+        }
 
         let mut virtio_blk = None;
-        let mut blk_desc = [virtio::VirtQDesc::empty(); 128];
-        let mut blk_avail = virtio::VirtQAvailable::empty();
-        let mut blk_used = virtio::VirtQUsed::empty();
 
         let mut virtio_entropy = None;
-        let mut entropy_desc = [virtio::VirtQDesc::empty(); 128];
-        let mut entropy_avail = virtio::VirtQAvailable::empty();
-        let mut entropy_used = virtio::VirtQUsed::empty();
+        let mut entropy_queues = [virtio::QueueMemory::empty()];
 
         for child in root.children_by_prop("compatible", |prop| prop.value == b"virtio,mmio\0") {
             if let Some(reg) = child.prop_by_name("reg") {
                 let (addr, _rest) = regs_to_usize(reg.value, address_cell);
                 if let Some(virtio) = unsafe { VirtIORegs::new(addr as *mut VirtIORegs) } {
-                    match virtio.device_id() {
+                    let device_id = virtio.device_id();
+                    // Only devices with a driver get their interrupt, failed ones give it back
+                    // in `init`.
+                    let has_driver =
+                        matches!(device_id, virtio::DeviceId::Blk | virtio::DeviceId::Entropy);
+                    let irq = gic::irqs_of(&child).next();
+                    if has_driver
+                        && irq.map_or(true, |spec| virtio.enable_interrupts(spec).is_err())
+                    {
+                        warn!(
+                            "virtio device at {:#x} has no interrupt, polling instead",
+                            addr
+                        );
+                    }
+                    match device_id {
                         virtio::DeviceId::Blk => {
-                            virtio_blk = virtio::VirtIOBlk::init(
+                            // The block device is shared with the flusher thread, so its queues
+                            // must outlive this frame.
+                            match virtio::VirtIOBlk::init(
                                 virtio,
-                                &mut blk_desc,
-                                &mut blk_avail,
-                                &mut blk_used,
-                            );
+                                Box::leak(Box::new([virtio::QueueMemory::empty()])),
+                            ) {
+                                Ok(blk) => {
+                                    debug!("virtio-blk features {:#x}", blk.features());
+                                    virtio_blk = Some(blk);
+                                }
+                                Err(e) => warn!("virtio-blk at {:#x}: {:?}", addr, e),
+                            }
                         }
                         virtio::DeviceId::Entropy => {
-                            virtio_entropy = virtio::VirtIOEntropy::init(
-                                virtio,
-                                &mut entropy_desc,
-                                &mut entropy_avail,
-                                &mut entropy_used,
-                            );
+                            // Always assigned, so the queue isn't still borrowed by an earlier
+                            // device on the next iteration.
+                            virtio_entropy =
+                                virtio::VirtIOEntropy::init(virtio, &mut entropy_queues)
+                                    .map_err(|e| warn!("virtio-rng at {:#x}: {:?}", addr, e))
+                                    .ok();
                         }
                         _ => {}
                     }
@@ -141,282 +270,69 @@ pub extern "C" fn kernel_main(dtb: &device_tree::DeviceTree) {
             }
         }
 
-        let mut virtio_entropy = virtio_entropy.unwrap();
+        let virtio_entropy = virtio_entropy.unwrap();
 
-        let virtio_blk_cfg: VirtIOBlkConfig = unsafe {
-            read_volatile(
-                &virtio_blk.as_ref().unwrap().regs.config.native() as *const u64 as *const _,
-            )
-        };
-        let _ = write!(uart, "Num. Sectors {:?}\n", virtio_blk_cfg.capacity);
+        let virtio_blk_cfg = VirtIOBlkConfig::read(virtio_blk.as_ref().unwrap().regs);
+        info!("Num. Sectors {:?}", virtio_blk_cfg.capacity);
 
-        let mut gbi = GlobalBlockInterface::new(virtio_blk.unwrap());
+        let gbi = Box::leak(Box::new(GlobalBlockInterface::new(virtio_blk.unwrap())));
         gbi.try_init().expect("Failed to init");
-        let mut fs = fs::FileSystem::new(&mut gbi);
+        let fs: &'static sched::Mutex<_> =
+            Box::leak(Box::new(sched::Mutex::new(fs::FileSystem::new(gbi))));
 
-        let mut curr_dir = fs
-            .root_dir(fs::FileMode::RW)
-            .expect("Failed to get root directory");
+        process::init(fs, &uart);
 
-        loop {
-            let _ = write!(uart, "$> ");
-            let mut buf = [0; 1024];
-            let line = uart.read_line(&mut buf, true);
-            let mut words = line.split(|c| *c == b' ');
-            let word = if let Some(word) = words.next() {
-                word
-            } else {
-                continue;
-            };
-            match word {
-                b"ls" => {
-                    let dir = fs.as_directory(curr_dir).unwrap();
-                    let entries = dir
-                        .entries()
-                        .map(|v| v.0)
-                        .map(|name| core::str::from_utf8(name).unwrap());
-                    for name in entries {
-                        let _ = write!(uart, "{}\n", name);
-                    }
-                }
-                b"open" => {
-                    let w = words.next().and_then(|w| core::str::from_utf8(w).ok());
-                    let file_name = if let Some(word) = w {
-                        word
-                    } else {
-                        let _ = writeln!(uart, "Usage: open <file_name> <kind=RW>");
-                        continue;
-                    };
-                    let mode = words
-                        .next()
-                        .and_then(|w| core::str::from_utf8(w).ok())
-                        .and_then(|w| fs::FileMode::from_str(w).ok())
-                        .unwrap_or(fs::FileMode::RW);
-                    let fd = match fs.open(curr_dir, &[file_name], mode) {
-                        Ok(fd) => fd,
-                        Err(err) => {
-                            let _ = writeln!(uart, "Open failed: {:?}", err);
-                            continue;
-                        }
-                    };
-                    let _ = writeln!(uart, "Opened file: {:?}", fd);
-                }
-                b"mkdir" => {
-                    let w = words.next().and_then(|w| core::str::from_utf8(w).ok());
-                    let dir_name = if let Some(word) = w {
-                        word
-                    } else {
-                        let _ = write!(uart, "Usage: mkdir <dir>");
-                        continue;
-                    };
-                    if let Err(e) = fs.mkdir(curr_dir, dir_name) {
-                        let _ = write!(uart, "mkdir failed: {:?}\n", e);
-                    }
-                }
-                b"rmdir" => {
-                    let dir_name = words.next().and_then(|w| core::str::from_utf8(w).ok());
-                    let dir_name = if let Some(dir_name) = dir_name {
-                        dir_name
-                    } else {
-                        let _ = writeln!(uart, "Usage: fwriterand <dir_name>");
-                        continue;
-                    };
-                    if let Err(err) = fs.rmdir(curr_dir, &[dir_name]) {
-                        let _ = writeln!(uart, "Failed to rmdir {}: {:?}", dir_name, err);
-                    }
-                }
-                b"cd" => {
-                    let w = words.next().and_then(|w| core::str::from_utf8(w).ok());
-                    let dir_name = if let Some(word) = w {
-                        word
-                    } else {
-                        curr_dir = fs.root_dir(fs::FileMode::RW).unwrap();
-                        continue;
-                    };
-                    let next_dir = match fs.open(curr_dir, &[dir_name], fs::FileMode::MustExist) {
-                        Ok(v) => v,
-                        Err(e) => {
-                            let _ = write!(uart, "cd failed: {:?}\n", e);
-                            continue;
-                        }
-                    };
-                    match fs.is_directory(next_dir) {
-                        Ok(true) => {}
-                        Ok(false) => {
-                            let _ =
-                                writeln!(uart, "cannot cd into {:?}: not a directory", dir_name);
-                            let _ = fs.close(next_dir);
-                            continue;
-                        }
-                        Err(err) => {
-                            let _ = write!(
-                                uart,
-                                "Could not determine if {:?} is dir: {:?}",
-                                dir_name, err
-                            );
-                            continue;
-                        }
-                    }
-                    if let Err(err) = fs.close(curr_dir) {
-                        let _ = write!(uart, "Could not properly close old directory: {:?}", err);
-                    }
-                    curr_dir = next_dir;
-                }
-                b"rand" => {
-                    let mut data: [u8; 16] = [0; 16];
-                    virtio_entropy.read(&mut data);
-                    let _ = write!(uart, "Random: {:?}\n", &data);
-                }
-                b"fread" => {
-                    let fd = words
-                        .next()
-                        .and_then(|fd| from_utf8(fd).ok())
-                        .and_then(|fd| fd.parse::<u32>().ok());
-                    let fd = if let Some(fd) = fd {
-                        fs::FileDescriptor::from(fd)
-                    } else {
-                        let _ = writeln!(uart, "Usage: fread <file_descriptor> <len=512>");
-                        continue;
-                    };
-                    let mut len = words
-                        .next()
-                        .and_then(|len| from_utf8(len).ok())
-                        .and_then(|len| len.parse::<usize>().ok())
-                        .unwrap_or(512);
-                    let mut data = [0; 512];
-                    while len > 0 {
-                        let rem = len.min(512);
-                        match fs.read(fd, &mut data[..rem]) {
-                            Ok(read) => {
-                                len -= read;
-                                uart.write_bytes(&data[..rem]);
-                            }
-                            Err(e) => {
-                                let _ = writeln!(uart, "Failed to read from {:?}: {:?}", fd, e);
-                                break;
-                            }
-                        }
-                    }
-                    loop {
-                        //gbi.block_device.read(sector, &mut data);
-                        if len > 512 {
-                            uart.write_bytes(&data);
-                            len -= 512;
-                        } else {
-                            uart.write_bytes(&data[..len]);
-                            uart.write_byte(b'\n');
-                            break;
-                        }
-                    }
-                }
-                b"fwriterand" => {
-                    let fd = words
-                        .next()
-                        .and_then(|fd| from_utf8(fd).ok())
-                        .and_then(|fd| fd.parse::<u32>().ok());
-                    let fd = if let Some(fd) = fd {
-                        fs::FileDescriptor::from(fd)
-                    } else {
-                        let _ = writeln!(uart, "Usage: fwriterand <file_descriptor> <len=512>");
-                        continue;
-                    };
-
-                    let mut len = words
-                        .next()
-                        .and_then(|len| from_utf8(len).ok())
-                        .and_then(|len| len.parse::<usize>().ok())
-                        .unwrap_or(512);
+        let flusher = sched::spawn("fs-flusher", move || loop {
+            sched::sleep(Duration::from_secs(5));
+            // There is nowhere to report a failure from here, the next flush will try again.
+            let _ = fs.lock().flush();
+        });
+        if let Err(e) = flusher {
+            error!("Failed to start the fs flusher: {:?}", e);
+        }
 
-                    // just read from entropy for now
-                    let mut data = [0u8; 512];
-                    while len > 0 {
-                        let rem = len.min(512);
-                        virtio_entropy.read(&mut data[..rem]);
-                        match fs.write(fd, &data[..rem]) {
-                            Ok(written) if written == rem => {
-                                len -= rem;
-                            }
-                            Ok(written) => {
-                                let _ = writeln!(
-                                    uart,
-                                    "Failed to write full amount to {:?}, expected: {}, got: {}",
-                                    fd, rem, written,
-                                );
-                                break;
-                            }
-                            Err(e) => {
-                                let _ = writeln!(uart, "Failed to write to {:?}: {:?}", fd, e);
-                                break;
-                            }
-                        }
-                    }
-                }
-                b"fclose" => {
-                    let fd = words
-                        .next()
-                        .and_then(|fd| from_utf8(fd).ok())
-                        .and_then(|fd| fd.parse::<u32>().ok());
-                    let fd = if let Some(fd) = fd {
-                        fs::FileDescriptor::from(fd)
-                    } else {
-                        let _ = uart.write_str("Usage: fclose <file_descriptor>");
-                        continue;
-                    };
-                    match fs.close(fd) {
-                        Ok(()) => {}
-                        Err(e) => {
-                            let _ = writeln!(uart, "Failed to close {:?}: {:?}", fd, e);
-                            break;
-                        }
-                    };
-                }
-                b"fseek" => {
-                    let fd = words
-                        .next()
-                        .and_then(|fd| from_utf8(fd).ok())
-                        .and_then(|fd| fd.parse::<u32>().ok());
-                    let fd = if let Some(fd) = fd {
-                        fs::FileDescriptor::from(fd)
-                    } else {
-                        let _ = uart.write_str("Usage: fseek <file_descriptor> <from_start=0>");
-                        continue;
-                    };
-                    let seek_pos = words
-                        .next()
-                        .and_then(|len| from_utf8(len).ok())
-                        .and_then(|len| len.parse::<u32>().ok())
-                        .unwrap_or(0);
-                    match fs.seek(fd, fs::SeekFrom::Start(seek_pos)) {
-                        Ok(()) => {}
-                        Err(err) => {
-                            let _ = writeln!(uart, "Failed to seek for {:?}: {:?}", fd, err);
-                        }
-                    }
-                }
-                b"exit" => {
-                    if let Err(e) = fs.flush() {
-                        let _ = writeln!(uart, "Failed to flush: {:?}", e);
-                    }
-                    break;
-                }
-                b"fs_stat" => {
-                    let _ = writeln!(uart, "FS Stats: {:?}", fs.fs_stats());
-                }
-                _ => {
-                    let _ = writeln!(
-                        uart,
-                        "Unknown command \"{}\"",
-                        from_utf8(line).unwrap_or("unknown")
-                    );
-                }
+        let ctx =
+            shell::Context::new(uart, fs, virtio_entropy).expect("Failed to get root directory");
+        let mut shell = shell::Shell::new(ctx);
+        match shell.source(&path::Path::parse(INIT_SCRIPT)) {
+            Ok(()) => {}
+            Err(shell::ShellErr::File(e)) if e.is_not_found() => {}
+            Err(e) => {
+                let _ = writeln!(shell.ctx.uart, "{}: {}", INIT_SCRIPT, e);
             }
         }
+        shell.run();
     }
 }
 
+/// Run by the shell at boot, if it exists.
+const INIT_SCRIPT: &str = "/init.sh";
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
 #[panic_handler]
 fn panic(panic_info: &PanicInfo<'_>) -> ! {
-    let mut uart = unsafe { uart::UART::new(0x0900_0000 as _) };
-    let _ = write!(uart, "Panic occurred: {}\n", panic_info);
-    loop {}
+    // Another panic while printing this one must not recurse.
+    let first = !PANICKING.swap(true, Ordering::Relaxed);
+    // Not logged, the panic may have happened with the dmesg buffer or the UART locked.
+    if let Some(mut uart) = log::emergency_console() {
+        let _ = writeln!(uart, "Panic occurred: {}", panic_info);
+        if first {
+            let _ = backtrace::print(&mut uart, None, backtrace::frame_pointer());
+        }
+        if backtrace::wait_for_debugger() {
+            let _ = writeln!(uart, "Waiting for a debugger");
+        }
+    }
+    exceptions::disable_interrupts();
+    if !backtrace::wait_for_debugger() {
+        psci::system_off();
+    }
+    // Keep handing control back to the debugger, there is nothing to continue to.
+    while first && gdb::enabled() {
+        gdb::breakpoint();
+    }
+    loop {
+        unsafe { core::arch::asm!("wfe") };
+    }
 }
@@ -0,0 +1,668 @@
+use crate::device_tree::Node;
+use core::arch::asm;
+
+pub const PAGE_SIZE: usize = 4096;
+const ENTRIES: usize = 512;
+
+/// With a 4KiB granule and T0SZ = 25 translation starts at level 1, giving a 39 bit VA space.
+const T0SZ: u64 = 25;
+const FIRST_LEVEL: usize = 1;
+
+/// Bytes mapped by a single entry at each level.
+const fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * (3 - level))
+}
+
+// Descriptor bits
+const VALID: u64 = 1 << 0;
+/// Table descriptor at levels 0-2, page descriptor at level 3.
+const TABLE_OR_PAGE: u64 = 1 << 1;
+const ATTR_INDEX_SHIFT: u64 = 2;
+const AP_EL0: u64 = 1 << 6;
+const AP_RO: u64 = 1 << 7;
+const SH_INNER: u64 = 0b11 << 8;
+const AF: u64 = 1 << 10;
+const PXN: u64 = 1 << 53;
+const UXN: u64 = 1 << 54;
+const ADDR_MASK: u64 = 0x0000_ffff_ffff_f000;
+
+/// User processes live in the upper half of the level 1 table, the kernel's identity map in
+/// the lower half. Every process shares the kernel's lower half entries.
+pub const USER_START: usize = 1 << (64 - T0SZ - 1);
+pub const USER_END: usize = 1 << (64 - T0SZ);
+
+/// Indices into MAIR_EL1.
+const MAIR_DEVICE: u64 = 0;
+const MAIR_NORMAL: u64 = 1;
+/// Normal write-back read/write-allocate at index 1. Index 0 is left as zero, which is
+/// Device-nGnRnE.
+const MAIR_VALUE: u64 = 0xff << (8 * MAIR_NORMAL);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Strongly ordered, uncached, for MMIO.
+    Device,
+    /// Cacheable RAM.
+    Normal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    pub kind: MemoryKind,
+    pub writable: bool,
+    pub executable: bool,
+    /// Accessible from EL0.
+    pub user: bool,
+}
+
+impl Attributes {
+    pub const KERNEL_TEXT: Self = Self::kernel(MemoryKind::Normal, false, true);
+    pub const KERNEL_RODATA: Self = Self::kernel(MemoryKind::Normal, false, false);
+    pub const KERNEL_DATA: Self = Self::kernel(MemoryKind::Normal, true, false);
+    pub const DEVICE: Self = Self::kernel(MemoryKind::Device, true, false);
+    pub const USER_TEXT: Self = Self::user(false, true);
+    pub const USER_RODATA: Self = Self::user(false, false);
+    pub const USER_DATA: Self = Self::user(true, false);
+
+    const fn kernel(kind: MemoryKind, writable: bool, executable: bool) -> Self {
+        Attributes {
+            kind,
+            writable,
+            executable,
+            user: false,
+        }
+    }
+
+    const fn user(writable: bool, executable: bool) -> Self {
+        Attributes {
+            kind: MemoryKind::Normal,
+            writable,
+            executable,
+            user: true,
+        }
+    }
+
+    /// Allows everything either of `self` and `other` allows, for a page shared by two mappings.
+    pub fn union(self, other: Self) -> Self {
+        Attributes {
+            kind: self.kind,
+            writable: self.writable || other.writable,
+            executable: self.executable || other.executable,
+            user: self.user && other.user,
+        }
+    }
+
+    /// Reads the attributes back out of a block or page descriptor.
+    fn from_descriptor(entry: u64) -> Self {
+        let kind = if (entry >> ATTR_INDEX_SHIFT) & 0b111 == MAIR_DEVICE {
+            MemoryKind::Device
+        } else {
+            MemoryKind::Normal
+        };
+        let user = entry & AP_EL0 != 0;
+        let never_execute = if user { UXN } else { PXN };
+        Attributes {
+            kind,
+            writable: entry & AP_RO == 0,
+            executable: entry & never_execute == 0,
+            user,
+        }
+    }
+
+    /// The lower and upper attribute bits of a block or page descriptor.
+    fn descriptor_bits(&self) -> u64 {
+        let mut bits = AF;
+        bits |= match self.kind {
+            MemoryKind::Device => MAIR_DEVICE << ATTR_INDEX_SHIFT,
+            MemoryKind::Normal => (MAIR_NORMAL << ATTR_INDEX_SHIFT) | SH_INNER,
+        };
+        if !self.writable {
+            bits |= AP_RO;
+        }
+        if self.user {
+            bits |= AP_EL0 | PXN;
+            if !self.executable {
+                bits |= UXN;
+            }
+        } else {
+            // Never let EL0 execute kernel memory.
+            bits |= UXN;
+            if !self.executable {
+                bits |= PXN;
+            }
+        }
+        bits
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapErr {
+    /// The address or size is not page aligned.
+    Unaligned,
+    /// The address does not fit in the translated address space.
+    OutOfRange,
+    /// Part of the range was already mapped.
+    AlreadyMapped,
+    /// Could not get memory for an intermediate table.
+    OutOfTables,
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+pub struct Table {
+    entries: [u64; ENTRIES],
+}
+
+impl Table {
+    pub const fn empty() -> Self {
+        Table {
+            entries: [0; ENTRIES],
+        }
+    }
+}
+
+/// A translation table hierarchy rooted at a level 1 table.
+#[derive(Debug)]
+pub struct AddressSpace {
+    root: *mut Table,
+}
+
+impl AddressSpace {
+    /// Creates an address space from a zeroed root table.
+    ///
+    /// # Safety
+    /// `root` must point to a zeroed, page aligned table which nothing else uses, and it must
+    /// outlive the address space along with every table later allocated into it.
+    pub unsafe fn from_root(root: *mut Table) -> Self {
+        AddressSpace { root }
+    }
+
+    pub fn root(&self) -> *mut Table {
+        self.root
+    }
+
+    /// Creates an address space for a user process. The kernel's half is shared with
+    /// `kernel_space`, so only the user half needs tables of its own.
+    pub fn new_user(alloc_table: &mut dyn FnMut() -> Option<*mut Table>) -> Result<Self, MapErr> {
+        let kernel = kernel_space().ok_or(MapErr::OutOfTables)?;
+        let root = alloc_table().ok_or(MapErr::OutOfTables)?;
+        unsafe {
+            root.write(Table::empty());
+            let half = index(USER_START, FIRST_LEVEL);
+            (*root).entries[..half].copy_from_slice(&(*kernel.root).entries[..half]);
+        }
+        Ok(AddressSpace { root })
+    }
+
+    /// Tears down the user half of this address space, passing every mapped page and every
+    /// table, including the root, to `free_frame`.
+    ///
+    /// Only 4KiB pages are expected in the user half.
+    ///
+    /// # Safety
+    /// The address space must not be active on any core, and every table and page in its user
+    /// half must have come from an allocator that `free_frame` returns them to.
+    pub unsafe fn destroy_user(self, free_frame: &mut dyn FnMut(usize)) {
+        let first = index(USER_START, FIRST_LEVEL);
+        for &l1 in &(*self.root).entries[first..] {
+            if l1 & VALID == 0 || l1 & TABLE_OR_PAGE == 0 {
+                continue;
+            }
+            let l2 = (l1 & ADDR_MASK) as *mut Table;
+            for &l2e in &(*l2).entries {
+                if l2e & VALID == 0 || l2e & TABLE_OR_PAGE == 0 {
+                    continue;
+                }
+                let l3 = (l2e & ADDR_MASK) as *mut Table;
+                for &page in &(*l3).entries {
+                    if page & VALID != 0 {
+                        free_frame((page & ADDR_MASK) as usize);
+                    }
+                }
+                free_frame(l3 as usize);
+            }
+            free_frame(l2 as usize);
+        }
+        free_frame(self.root as usize);
+    }
+
+    /// Identity maps `[addr, addr + size)`.
+    pub fn identity_map(
+        &mut self,
+        addr: usize,
+        size: usize,
+        attrs: Attributes,
+        alloc_table: &mut dyn FnMut() -> Option<*mut Table>,
+    ) -> Result<(), MapErr> {
+        self.map(addr, addr, size, attrs, alloc_table)
+    }
+
+    /// Maps `[va, va + size)` to `[pa, pa + size)`, using the largest blocks alignment allows.
+    pub fn map(
+        &mut self,
+        mut va: usize,
+        mut pa: usize,
+        size: usize,
+        attrs: Attributes,
+        alloc_table: &mut dyn FnMut() -> Option<*mut Table>,
+    ) -> Result<(), MapErr> {
+        if va % PAGE_SIZE != 0 || pa % PAGE_SIZE != 0 || size % PAGE_SIZE != 0 {
+            return Err(MapErr::Unaligned);
+        }
+        let end = va.checked_add(size).ok_or(MapErr::OutOfRange)?;
+        if end > 1 << (64 - T0SZ) {
+            return Err(MapErr::OutOfRange);
+        }
+        let bits = attrs.descriptor_bits();
+        while va < end {
+            // 1GiB blocks keep large RAM from using up the boot tables.
+            let level = (FIRST_LEVEL..3)
+                .find(|&level| {
+                    let size = level_size(level);
+                    va % size == 0 && pa % size == 0 && end - va >= size
+                })
+                .unwrap_or(3);
+            let entry = unsafe { self.walk(va, level, alloc_table)? };
+            if *entry & VALID != 0 {
+                return Err(MapErr::AlreadyMapped);
+            }
+            let kind = if level == 3 { TABLE_OR_PAGE } else { 0 };
+            *entry = (pa as u64 & ADDR_MASK) | bits | kind | VALID;
+            va += level_size(level);
+            pa += level_size(level);
+        }
+        Ok(())
+    }
+
+    /// Returns the entry for `va` in the table at `level`, creating intermediate tables.
+    unsafe fn walk(
+        &mut self,
+        va: usize,
+        level: usize,
+        alloc_table: &mut dyn FnMut() -> Option<*mut Table>,
+    ) -> Result<&mut u64, MapErr> {
+        let mut table = self.root;
+        for curr in FIRST_LEVEL..level {
+            let entry = &mut (*table).entries[index(va, curr)];
+            if *entry & VALID == 0 {
+                let next = alloc_table().ok_or(MapErr::OutOfTables)?;
+                next.write(Table::empty());
+                *entry = (next as u64 & ADDR_MASK) | TABLE_OR_PAGE | VALID;
+            } else if *entry & TABLE_OR_PAGE == 0 {
+                // Covered by a block already
+                return Err(MapErr::AlreadyMapped);
+            }
+            table = (*entry & ADDR_MASK) as *mut Table;
+        }
+        Ok(&mut (*table).entries[index(va, level)])
+    }
+
+    /// Finds the block or page descriptor mapping `va` and the level it was found at.
+    fn lookup(&self, va: usize) -> Option<(u64, usize)> {
+        let mut table = self.root;
+        for level in FIRST_LEVEL..=3 {
+            let entry = unsafe { (*table).entries[index(va, level)] };
+            if entry & VALID == 0 {
+                return None;
+            }
+            if level == 3 || entry & TABLE_OR_PAGE == 0 {
+                return Some((entry, level));
+            }
+            table = (entry & ADDR_MASK) as *mut Table;
+        }
+        None
+    }
+
+    /// Makes the page or block mapping `va` writable, or read only again, returning false if
+    /// nothing maps it. This is how breakpoints get written into kernel text.
+    ///
+    /// # Safety
+    /// While the mapping is writable, stray writes to it go unnoticed, even into kernel text,
+    /// so it must be made read only again as soon as the write that needed it is done. Every
+    /// other page in the same block mapping changes with it.
+    pub unsafe fn set_writable(&self, va: usize, writable: bool) -> bool {
+        let mut table = self.root;
+        for level in FIRST_LEVEL..=3 {
+            let entry = unsafe { &mut (*table).entries[index(va, level)] };
+            if *entry & VALID == 0 {
+                return false;
+            }
+            if level == 3 || *entry & TABLE_OR_PAGE == 0 {
+                if writable {
+                    *entry &= !AP_RO;
+                } else {
+                    *entry |= AP_RO;
+                }
+                unsafe {
+                    asm!(
+                        "dsb ishst",
+                        "tlbi vaae1is, {}",
+                        "dsb ish",
+                        "isb",
+                        in(reg) (va >> 12) as u64,
+                    )
+                };
+                return true;
+            }
+            table = (*entry & ADDR_MASK) as *mut Table;
+        }
+        false
+    }
+
+    /// The attributes `va` is mapped with, if it is mapped.
+    pub fn attributes(&self, va: usize) -> Option<Attributes> {
+        self.lookup(va)
+            .map(|(entry, _)| Attributes::from_descriptor(entry))
+    }
+
+    /// Changes the attributes of the user page mapping `va`, returning false if `va` is not a
+    /// mapped page in the user half.
+    pub fn protect(&mut self, va: usize, attrs: Attributes) -> bool {
+        if !(USER_START..USER_END).contains(&va) {
+            return false;
+        }
+        let mut table = self.root;
+        for level in FIRST_LEVEL..3 {
+            let entry = unsafe { (*table).entries[index(va, level)] };
+            if entry & VALID == 0 || entry & TABLE_OR_PAGE == 0 {
+                return false;
+            }
+            table = (entry & ADDR_MASK) as *mut Table;
+        }
+        let entry = unsafe { &mut (*table).entries[index(va, 3)] };
+        if *entry & VALID == 0 {
+            return false;
+        }
+        *entry = (*entry & ADDR_MASK) | attrs.descriptor_bits() | TABLE_OR_PAGE | VALID;
+        unsafe {
+            asm!(
+                "dsb ishst",
+                "tlbi vaae1is, {}",
+                "dsb ish",
+                "isb",
+                in(reg) (va >> 12) as u64,
+            )
+        };
+        true
+    }
+
+    /// Translates `va` to a physical address if it is mapped.
+    pub fn translate(&self, va: usize) -> Option<usize> {
+        let (entry, level) = self.lookup(va)?;
+        let offset = va % level_size(level);
+        Some((entry & ADDR_MASK) as usize & !(level_size(level) - 1) | offset)
+    }
+
+    /// Returns whether EL0 may access all of `[va, va + len)`, and write to it if `write`.
+    pub fn user_accessible(&self, va: usize, len: usize, write: bool) -> bool {
+        let end = match va.checked_add(len) {
+            Some(end) if va >= USER_START && end <= USER_END => end,
+            _ => return false,
+        };
+        (page_down(va)..end).step_by(PAGE_SIZE).all(|page| {
+            self.lookup(page)
+                .map(|(entry, _)| entry & AP_EL0 != 0 && (!write || entry & AP_RO == 0))
+                .unwrap_or(false)
+        })
+    }
+
+    /// Installs this address space in TTBR0_EL1 and flushes stale translations.
+    ///
+    /// # Safety
+    /// The kernel's half must be mapped as in `kernel_space`, so the running code, its stack and
+    /// everything it refers to stay mapped, and the address space must stay alive for as long as
+    /// it is installed.
+    pub unsafe fn activate(&self) {
+        asm!(
+            "msr ttbr0_el1, {}",
+            "isb",
+            "tlbi vmalle1",
+            "dsb ish",
+            "isb",
+            in(reg) self.root as u64,
+        );
+    }
+}
+
+const fn index(va: usize, level: usize) -> usize {
+    (va >> (12 + 9 * (3 - level))) & (ENTRIES - 1)
+}
+
+/// Tables used for the kernel's own address space, before any allocator exists.
+const NUM_BOOT_TABLES: usize = 16;
+static mut BOOT_TABLES: [Table; NUM_BOOT_TABLES] = [Table::empty(); NUM_BOOT_TABLES];
+static mut BOOT_TABLES_USED: usize = 0;
+
+fn alloc_boot_table() -> Option<*mut Table> {
+    unsafe {
+        let table = BOOT_TABLES.get_mut(BOOT_TABLES_USED)?;
+        BOOT_TABLES_USED += 1;
+        Some(table as *mut Table)
+    }
+}
+
+static mut KERNEL_SPACE: Option<AddressSpace> = None;
+
+/// The address space every kernel thread runs in.
+pub fn kernel_space() -> Option<&'static AddressSpace> {
+    unsafe { KERNEL_SPACE.as_ref() }
+}
+
+/// Whether the kernel can read `addr`, or write it if `write`, according to the MMU.
+pub fn accessible(addr: usize, write: bool) -> bool {
+    let par: u64;
+    unsafe {
+        if write {
+            asm!("at s1e1w, {0}", "isb", "mrs {1}, par_el1", in(reg) addr, out(reg) par);
+        } else {
+            asm!("at s1e1r, {0}", "isb", "mrs {1}, par_el1", in(reg) addr, out(reg) par);
+        }
+    }
+    // PAR_EL1.F is set if the translation faulted.
+    par & 1 == 0
+}
+
+/// Cleans the data cache lines covering `[addr, addr + len)` to the point of unification, where
+/// instruction fetches see them.
+pub fn clean_dcache(addr: usize, len: usize) {
+    let ctr: u64;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr) };
+    let line = 4 << ((ctr >> 16) & 0xf);
+    unsafe {
+        for at in (addr & !(line - 1)..addr + len).step_by(line) {
+            asm!("dc cvau, {}", in(reg) at);
+        }
+        asm!("dsb ish");
+    }
+}
+
+extern "C" {
+    static __kernel_start: u8;
+    static __text_end: u8;
+    static __rodata_end: u8;
+    static LD_STACK_PTR: u8;
+}
+
+/// Physical ranges of the kernel image: `(start, text_end, rodata_end, end)`.
+pub fn kernel_image() -> (usize, usize, usize, usize) {
+    unsafe {
+        (
+            &__kernel_start as *const u8 as usize,
+            &__text_end as *const u8 as usize,
+            &__rodata_end as *const u8 as usize,
+            &LD_STACK_PTR as *const u8 as usize,
+        )
+    }
+}
+
+/// Iterates over the `(address, size)` ranges of every `/memory` node.
+pub fn memory_regions<'a>(root: &Node<'a>) -> impl Iterator<Item = (usize, usize)> + 'a {
+    let address_cells = root.address_cells();
+    let size_cells = root.size_cells();
+    root.children()
+        .filter(|child| {
+            child
+                .prop_by_name("device_type")
+                .map(|p| p.contains_str("memory"))
+                .unwrap_or(false)
+        })
+        .filter_map(|child| child.prop_by_name("reg"))
+        .flat_map(move |reg| reg.reg(address_cells, size_cells))
+}
+
+pub const fn page_down(v: usize) -> usize {
+    v & !(PAGE_SIZE - 1)
+}
+
+pub const fn page_up(v: usize) -> usize {
+    (v + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+/// Builds the kernel's identity map from the device tree and turns on the MMU and caches.
+///
+/// RAM is mapped as normal memory with the kernel image split into read-only executable text,
+/// read-only rodata, and writable non-executable data. The `reg` ranges of every other node
+/// under the root are mapped as device memory.
+pub fn init(root: &Node) -> Result<(), MapErr> {
+    let root_table = alloc_boot_table().ok_or(MapErr::OutOfTables)?;
+    let mut space = unsafe { AddressSpace::from_root(root_table) };
+    let alloc = &mut alloc_boot_table;
+
+    let (kernel_start, text_end, rodata_end, kernel_end) = kernel_image();
+    let kernel_end = page_up(kernel_end);
+    space.identity_map(
+        kernel_start,
+        text_end - kernel_start,
+        Attributes::KERNEL_TEXT,
+        alloc,
+    )?;
+    space.identity_map(
+        text_end,
+        rodata_end - text_end,
+        Attributes::KERNEL_RODATA,
+        alloc,
+    )?;
+    space.identity_map(
+        rodata_end,
+        kernel_end - rodata_end,
+        Attributes::KERNEL_DATA,
+        alloc,
+    )?;
+
+    for (start, size) in memory_regions(root) {
+        let (start, end) = (page_up(start), page_down(start + size));
+        // Everything in RAM other than the kernel image
+        for (s, e) in [(start, end.min(kernel_start)), (start.max(kernel_end), end)] {
+            if s < e {
+                space.identity_map(s, e - s, Attributes::KERNEL_DATA, alloc)?;
+            }
+        }
+    }
+
+    let address_cells = root.address_cells();
+    let size_cells = root.size_cells();
+    let devices = root.children().filter(|child| {
+        !child.name.starts_with(b"memory") && child.prop_by_name("compatible").is_some()
+    });
+    for device in devices {
+        let reg = if let Some(reg) = device.prop_by_name("reg") {
+            reg
+        } else {
+            continue;
+        };
+        for (addr, size) in reg.reg(address_cells, size_cells) {
+            let (start, end) = (page_down(addr), page_up(addr + size));
+            // Keep the null page unmapped so that null dereferences still fault.
+            if start == 0 {
+                continue;
+            }
+            match space.identity_map(start, end - start, Attributes::DEVICE, alloc) {
+                Ok(()) => {}
+                // Small devices often share a page with their neighbours, so fill in page by
+                // page around whatever is already there.
+                Err(MapErr::AlreadyMapped) => {
+                    for page in (start..end).step_by(PAGE_SIZE) {
+                        match space.identity_map(page, PAGE_SIZE, Attributes::DEVICE, alloc) {
+                            Ok(()) | Err(MapErr::AlreadyMapped) => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    unsafe {
+        enable(&space);
+        KERNEL_SPACE = Some(space);
+    }
+    Ok(())
+}
+
+/// Translation registers of the running core, for bringing up others with the same setup.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct CpuState {
+    pub ttbr0: u64,
+    pub tcr: u64,
+    pub mair: u64,
+    pub sctlr: u64,
+}
+
+pub fn cpu_state() -> CpuState {
+    let (ttbr0, tcr, mair, sctlr): (u64, u64, u64, u64);
+    unsafe {
+        asm!(
+            "mrs {}, ttbr0_el1",
+            "mrs {}, tcr_el1",
+            "mrs {}, mair_el1",
+            "mrs {}, sctlr_el1",
+            out(reg) ttbr0,
+            out(reg) tcr,
+            out(reg) mair,
+            out(reg) sctlr,
+        );
+    }
+    CpuState {
+        ttbr0,
+        tcr,
+        mair,
+        sctlr,
+    }
+}
+
+/// Programs MAIR/TCR/TTBR0 and sets SCTLR_EL1.{M, C, I}.
+unsafe fn enable(space: &AddressSpace) {
+    let mmfr0: u64;
+    asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0);
+    // Physical address size supported by the cpu
+    let ips = (mmfr0 & 0b111).min(0b101);
+    let tcr = T0SZ
+        // Inner/outer write-back write-allocate, inner shareable walks, 4KiB granule
+        | (0b01 << 8)
+        | (0b01 << 10)
+        | (0b11 << 12)
+        // No walks through TTBR1
+        | (1 << 23)
+        | (ips << 32);
+    asm!(
+        "msr mair_el1, {mair}",
+        "msr tcr_el1, {tcr}",
+        "msr ttbr0_el1, {ttbr}",
+        "dsb ish",
+        "isb",
+        "tlbi vmalle1",
+        "ic iallu",
+        "dsb ish",
+        "isb",
+        mair = in(reg) MAIR_VALUE,
+        tcr = in(reg) tcr,
+        ttbr = in(reg) space.root as u64,
+    );
+    let mut sctlr: u64;
+    asm!("mrs {}, sctlr_el1", out(reg) sctlr);
+    // M | C | I
+    sctlr |= (1 << 0) | (1 << 2) | (1 << 12);
+    asm!("msr sctlr_el1, {}", "isb", in(reg) sctlr);
+}
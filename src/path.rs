@@ -0,0 +1,108 @@
+use alloc::{string::String, vec::Vec};
+use core::fmt;
+
+/// A `/` separated path with `.` and `..` already applied.
+///
+/// Relative paths can still start with `..` since there is nothing to cancel them against
+/// until they are joined onto a directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Path {
+    absolute: bool,
+    components: Vec<String>,
+}
+
+impl Path {
+    /// The root directory, `/`.
+    pub fn root() -> Self {
+        Path {
+            absolute: true,
+            components: Vec::new(),
+        }
+    }
+
+    /// Parses and normalizes `s`. Empty components, as in `a//b` or a trailing `/`, are
+    /// ignored.
+    pub fn parse(s: &str) -> Self {
+        let mut path = Path {
+            absolute: s.starts_with('/'),
+            components: Vec::new(),
+        };
+        for c in s.split('/') {
+            path.push(c);
+        }
+        path
+    }
+
+    /// Appends a single component, applying it if it is `.` or `..`.
+    fn push(&mut self, c: &str) {
+        match c {
+            "" | "." => {}
+            ".." => match self.components.last().map(String::as_str) {
+                Some("..") | None if !self.absolute => self.components.push(String::from("..")),
+                // `/..` is `/`.
+                None => {}
+                Some(_) => {
+                    self.components.pop();
+                }
+            },
+            c => self.components.push(String::from(c)),
+        }
+    }
+
+    pub fn is_absolute(&self) -> bool {
+        self.absolute
+    }
+
+    /// Whether this is `/`.
+    pub fn is_root(&self) -> bool {
+        self.absolute && self.components.is_empty()
+    }
+
+    /// Resolves `other` relative to this path. An absolute `other` is returned as is.
+    pub fn join(&self, other: &Path) -> Path {
+        if other.absolute {
+            return other.clone();
+        }
+        let mut path = self.clone();
+        for c in &other.components {
+            path.push(c);
+        }
+        path
+    }
+
+    /// The components in the form `FileSystem` takes them.
+    pub fn components(&self) -> Vec<&str> {
+        self.components.iter().map(String::as_str).collect()
+    }
+
+    /// The last component, unless this is `/` or ends in `..`.
+    pub fn file_name(&self) -> Option<&str> {
+        match self.components.last().map(String::as_str) {
+            Some("..") | None => None,
+            name => name,
+        }
+    }
+
+    /// Everything but the last component.
+    pub fn parent(&self) -> Option<Path> {
+        self.file_name()?;
+        let mut parent = self.clone();
+        parent.components.pop();
+        Some(parent)
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.components.is_empty() {
+            return f.write_str(if self.absolute { "/" } else { "." });
+        }
+        for (i, c) in self.components.iter().enumerate() {
+            if i > 0 || self.absolute {
+                f.write_str("/")?;
+            }
+            f.write_str(c)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,355 @@
+use crate::exceptions::TrapFrame;
+use crate::mmu::{self, page_down, page_up, AddressSpace, Attributes, MapErr, PAGE_SIZE};
+use crate::sched::{self, JoinHandle, ThreadId};
+use crate::{frames, fs, uart, virtio};
+use alloc::{string::String, vec::Vec};
+use core::arch::asm;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// The file system every process shares.
+pub type KernelFs = fs::FileSystem<'static, virtio::VirtIOBlk<'static>>;
+
+/// Maximum number of open files per process.
+pub const MAX_FDS: usize = 32;
+/// The user stack grows down from the very top of the user half.
+pub const USER_STACK_TOP: usize = mmu::USER_END;
+pub const USER_STACK_PAGES: usize = 16;
+/// SPSR for entering EL0t with all exceptions unmasked.
+const SPSR_EL0T: u64 = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u32);
+
+impl Pid {
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+}
+
+/// What a per-process file descriptor refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdEntry {
+    Console,
+    /// An entry in the file system's shared table of open files.
+    File(fs::FileDescriptor),
+}
+
+/// Maps a process's file descriptors to open files. Descriptors 0, 1 and 2 start out as the
+/// console.
+#[derive(Debug)]
+pub struct FdTable {
+    entries: [Option<FdEntry>; MAX_FDS],
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        let mut entries = [None; MAX_FDS];
+        entries[..3].fill(Some(FdEntry::Console));
+        FdTable { entries }
+    }
+
+    pub fn get(&self, fd: usize) -> Option<FdEntry> {
+        self.entries.get(fd).copied().flatten()
+    }
+
+    /// Stores `entry` in the lowest free descriptor.
+    pub fn insert(&mut self, entry: FdEntry) -> Option<usize> {
+        let fd = self.entries.iter().position(|e| e.is_none())?;
+        self.entries[fd] = Some(entry);
+        Some(fd)
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Option<FdEntry> {
+        self.entries.get_mut(fd)?.take()
+    }
+
+    fn drain(&mut self) -> impl Iterator<Item = FdEntry> + '_ {
+        self.entries.iter_mut().filter_map(|e| e.take())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessErr {
+    NotInitialized,
+    NoMemory,
+    Map(MapErr),
+    /// The range is not inside the user half of the address space.
+    BadAddress,
+    Spawn(sched::SpawnErr),
+}
+
+impl From<MapErr> for ProcessErr {
+    fn from(e: MapErr) -> Self {
+        ProcessErr::Map(e)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitErr {
+    NoSuchProcess,
+    AlreadyWaited,
+    Join(sched::JoinErr),
+}
+
+pub struct Process {
+    pid: Pid,
+    pub name: String,
+    /// `None` once the process has exited and its memory has been released.
+    space: Option<AddressSpace>,
+    pub files: FdTable,
+    thread: Option<ThreadId>,
+    join: Option<JoinHandle>,
+    exit_code: Option<isize>,
+}
+
+// The address space and file table are only touched by the process's own thread while it runs,
+// and by whoever waits for it once it has exited.
+unsafe impl Send for Process {}
+
+fn alloc_table() -> Option<*mut mmu::Table> {
+    frames::alloc().map(|addr| addr as *mut mmu::Table)
+}
+
+impl Process {
+    /// Creates a process with an empty user address space.
+    pub fn new(name: String) -> Result<Self, ProcessErr> {
+        let space = AddressSpace::new_user(&mut alloc_table)?;
+        Ok(Process {
+            pid: Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed)),
+            name,
+            space: Some(space),
+            files: FdTable::new(),
+            thread: None,
+            join: None,
+            exit_code: None,
+        })
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn space(&self) -> Option<&AddressSpace> {
+        self.space.as_ref()
+    }
+
+    /// Backs every page overlapping `[va, va + len)` with zeroed memory. Pages which are already
+    /// mapped are kept, widened to allow whatever `attrs` allows as well, as where one segment
+    /// ends and the next begins on the same page.
+    pub fn map_zeroed(
+        &mut self,
+        va: usize,
+        len: usize,
+        attrs: Attributes,
+    ) -> Result<(), ProcessErr> {
+        let space = self.space.as_mut().ok_or(ProcessErr::BadAddress)?;
+        let end = va.checked_add(len).ok_or(ProcessErr::BadAddress)?;
+        if va < mmu::USER_START || end > mmu::USER_END {
+            return Err(ProcessErr::BadAddress);
+        }
+        for page in (page_down(va)..page_up(end)).step_by(PAGE_SIZE) {
+            if let Some(current) = space.attributes(page) {
+                let merged = current.union(attrs);
+                if merged != current && !space.protect(page, merged) {
+                    return Err(ProcessErr::BadAddress);
+                }
+                continue;
+            }
+            let frame = frames::alloc_zeroed().ok_or(ProcessErr::NoMemory)?;
+            if let Err(e) = space.map(page, frame, PAGE_SIZE, attrs, &mut alloc_table) {
+                frames::free(frame, 1);
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Copies `data` into this process's memory at `va`, which must already be mapped.
+    pub fn write(&mut self, mut va: usize, mut data: &[u8]) -> Result<(), ProcessErr> {
+        let space = self.space.as_ref().ok_or(ProcessErr::BadAddress)?;
+        if !space.user_accessible(va, data.len(), false) {
+            return Err(ProcessErr::BadAddress);
+        }
+        while !data.is_empty() {
+            // RAM is identity mapped, so the physical address can be written directly.
+            let pa = space.translate(va).ok_or(ProcessErr::BadAddress)?;
+            let n = data.len().min(PAGE_SIZE - va % PAGE_SIZE);
+            unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), pa as *mut u8, n) };
+            va += n;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    /// Cleans `[va, va + len)` to the point of unification, so that instructions written there
+    /// with `write` can be fetched once the instruction cache is invalidated.
+    pub fn clean_dcache(&self, mut va: usize, mut len: usize) -> Result<(), ProcessErr> {
+        let space = self.space.as_ref().ok_or(ProcessErr::BadAddress)?;
+        while len > 0 {
+            let pa = space.translate(va).ok_or(ProcessErr::BadAddress)?;
+            let n = len.min(PAGE_SIZE - va % PAGE_SIZE);
+            mmu::clean_dcache(pa, n);
+            va += n;
+            len -= n;
+        }
+        Ok(())
+    }
+
+    /// Maps the user stack, returning the initial stack pointer.
+    pub fn map_stack(&mut self) -> Result<usize, ProcessErr> {
+        let bottom = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+        self.map_zeroed(bottom, USER_STACK_PAGES * PAGE_SIZE, Attributes::USER_DATA)?;
+        Ok(USER_STACK_TOP)
+    }
+
+    /// Closes every open file and frees the address space.
+    fn release(&mut self) {
+        if let Some(fs) = fs() {
+            let mut fs = fs.lock();
+            for entry in self.files.drain() {
+                if let FdEntry::File(fd) = entry {
+                    let _ = fs.close(fd);
+                }
+            }
+        }
+        self.free_memory();
+    }
+
+    fn free_memory(&mut self) {
+        if let Some(space) = self.space.take() {
+            unsafe { space.destroy_user(&mut |frame| frames::free(frame, 1)) };
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        // Files need the file system lock, which the owner may be holding, so only memory is
+        // released here. Processes which ran are released by `exit_current` or `wait`.
+        self.free_memory();
+    }
+}
+
+static NEXT_PID: AtomicU32 = AtomicU32::new(1);
+static PROCESSES: sched::Mutex<Vec<Process>> = sched::Mutex::new(Vec::new());
+static mut FS: Option<&'static sched::Mutex<KernelFs>> = None;
+static CONSOLE: AtomicUsize = AtomicUsize::new(0);
+
+/// Gives processes access to the file system and to the console behind descriptors 0-2.
+pub fn init(fs: &'static sched::Mutex<KernelFs>, console: &uart::UART) {
+    unsafe { FS = Some(fs) };
+    CONSOLE.store(console.base() as usize, Ordering::Relaxed);
+}
+
+pub fn fs() -> Option<&'static sched::Mutex<KernelFs>> {
+    unsafe { FS }
+}
+
+pub fn console() -> Option<uart::UART> {
+    match CONSOLE.load(Ordering::Relaxed) {
+        0 => None,
+        base => Some(unsafe { uart::UART::new(base as _) }),
+    }
+}
+
+/// Runs `f` on the process the calling thread belongs to.
+pub fn with_current<R>(f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let thread = sched::current()?;
+    let mut processes = PROCESSES.lock();
+    processes
+        .iter_mut()
+        .find(|p| p.thread == Some(thread))
+        .map(f)
+}
+
+/// Starts `process` at `entry` in EL0 with the stack pointer `sp` and `args` in x0 onwards.
+pub fn spawn(process: Process, entry: usize, sp: usize, args: &[u64]) -> Result<Pid, ProcessErr> {
+    let pid = process.pid;
+    let mut regs = [0; 31];
+    regs[..args.len()].copy_from_slice(args);
+    let frame = TrapFrame {
+        regs,
+        sp_el0: sp as u64,
+        elr: entry as u64,
+        spsr: SPSR_EL0T,
+        fp_regs: [0; 32],
+        fpsr: 0,
+        fpcr: 0,
+    };
+    // Hold the table until the thread is recorded, so the thread always finds itself in it.
+    let mut processes = PROCESSES.lock();
+    processes.push(process);
+    let handle = sched::spawn("user", move || enter_user(pid, frame));
+    let process = processes.last_mut().unwrap();
+    match handle {
+        Ok(handle) => {
+            process.thread = Some(handle.id());
+            process.join = Some(handle);
+            Ok(pid)
+        }
+        Err(e) => {
+            // It never ran, so it cannot have opened anything.
+            processes.pop();
+            Err(ProcessErr::Spawn(e))
+        }
+    }
+}
+
+fn enter_user(pid: Pid, frame: TrapFrame) -> ! {
+    let root = {
+        let processes = PROCESSES.lock();
+        processes
+            .iter()
+            .find(|p| p.pid == pid)
+            .and_then(|p| p.space.as_ref())
+            .map(|space| space.root())
+    };
+    let root = if let Some(root) = root {
+        root
+    } else {
+        sched::exit()
+    };
+    unsafe {
+        sched::set_address_space(Some(root));
+        // Unwind straight into the tail of the exception handler, which restores `frame` and
+        // erets to EL0. Traps back into the kernel will reuse this thread's stack from here.
+        asm!(
+            "mov sp, {frame}",
+            "b exception_return",
+            frame = in(reg) &frame as *const TrapFrame,
+            options(noreturn),
+        )
+    }
+}
+
+/// Ends the calling process with `code`, releasing everything it owns.
+pub fn exit_current(code: isize) -> ! {
+    unsafe { sched::set_address_space(None) };
+    with_current(|process| {
+        process.exit_code = Some(code);
+        process.release();
+    });
+    sched::exit()
+}
+
+/// Blocks until `pid` exits, returning its exit code.
+pub fn wait(pid: Pid) -> Result<isize, WaitErr> {
+    let handle = PROCESSES
+        .lock()
+        .iter_mut()
+        .find(|p| p.pid == pid)
+        .ok_or(WaitErr::NoSuchProcess)?
+        .join
+        .take()
+        .ok_or(WaitErr::AlreadyWaited)?;
+    handle.join().map_err(WaitErr::Join)?;
+    let mut processes = PROCESSES.lock();
+    let i = processes
+        .iter()
+        .position(|p| p.pid == pid)
+        .ok_or(WaitErr::NoSuchProcess)?;
+    let mut process = processes.remove(i);
+    // Nothing is left to release unless the thread ended without going through `exit_current`.
+    process.release();
+    Ok(process.exit_code.unwrap_or(-1))
+}
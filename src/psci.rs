@@ -0,0 +1,174 @@
+use crate::device_tree::Node;
+use core::arch::asm;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+// SMC32 function IDs
+const PSCI_VERSION: u32 = 0x8400_0000;
+const SYSTEM_OFF: u32 = 0x8400_0008;
+const SYSTEM_RESET: u32 = 0x8400_0009;
+// SMC64 function IDs
+const CPU_SUSPEND: u32 = 0xc400_0001;
+const CPU_ON: u32 = 0xc400_0003;
+const AFFINITY_INFO: u32 = 0xc400_0004;
+
+/// How PSCI calls reach the firmware, from the `method` property of `/psci`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Conduit {
+    Hvc,
+    Smc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PsciErr {
+    NotSupported,
+    InvalidParameters,
+    Denied,
+    AlreadyOn,
+    OnPending,
+    InternalFailure,
+    NotPresent,
+    Disabled,
+    InvalidAddress,
+    /// The firmware returned a code the spec does not define.
+    Unknown(i64),
+    /// There is no `/psci` node, or its method is not one we know.
+    NoConduit,
+}
+
+impl PsciErr {
+    fn from_code(code: i64) -> Self {
+        match code {
+            -1 => PsciErr::NotSupported,
+            -2 => PsciErr::InvalidParameters,
+            -3 => PsciErr::Denied,
+            -4 => PsciErr::AlreadyOn,
+            -5 => PsciErr::OnPending,
+            -6 => PsciErr::InternalFailure,
+            -7 => PsciErr::NotPresent,
+            -8 => PsciErr::Disabled,
+            -9 => PsciErr::InvalidAddress,
+            v => PsciErr::Unknown(v),
+        }
+    }
+}
+
+/// The power state of a core as reported by `AFFINITY_INFO`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffinityState {
+    On,
+    Off,
+    OnPending,
+}
+
+const NO_CONDUIT: u8 = 0;
+const HVC: u8 = 1;
+const SMC: u8 = 2;
+static CONDUIT: AtomicU8 = AtomicU8::new(NO_CONDUIT);
+
+/// Reads the conduit from the `method` of the `/psci` node.
+pub fn init(root: &Node) -> Option<Conduit> {
+    let method = root.child_by_name("psci")?.prop_by_name("method")?;
+    let conduit = if method.contains_str("hvc") {
+        Conduit::Hvc
+    } else if method.contains_str("smc") {
+        Conduit::Smc
+    } else {
+        return None;
+    };
+    let v = match conduit {
+        Conduit::Hvc => HVC,
+        Conduit::Smc => SMC,
+    };
+    CONDUIT.store(v, Ordering::Relaxed);
+    Some(conduit)
+}
+
+pub fn conduit() -> Option<Conduit> {
+    match CONDUIT.load(Ordering::Relaxed) {
+        HVC => Some(Conduit::Hvc),
+        SMC => Some(Conduit::Smc),
+        _ => None,
+    }
+}
+
+/// Makes a PSCI call with up to three arguments, returning the raw value of x0.
+fn call(function: u32, a1: u64, a2: u64, a3: u64) -> Result<i64, PsciErr> {
+    let mut ret = function as u64;
+    unsafe {
+        match conduit().ok_or(PsciErr::NoConduit)? {
+            Conduit::Hvc => asm!(
+                "hvc #0",
+                inout("x0") ret,
+                in("x1") a1,
+                in("x2") a2,
+                in("x3") a3,
+                clobber_abi("C"),
+            ),
+            Conduit::Smc => asm!(
+                "smc #0",
+                inout("x0") ret,
+                in("x1") a1,
+                in("x2") a2,
+                in("x3") a3,
+                clobber_abi("C"),
+            ),
+        }
+    }
+    Ok(ret as i64)
+}
+
+/// Like `call` but for functions which return 0 or an error code.
+fn call_status(function: u32, a1: u64, a2: u64, a3: u64) -> Result<(), PsciErr> {
+    match call(function, a1, a2, a3)? {
+        0 => Ok(()),
+        code => Err(PsciErr::from_code(code)),
+    }
+}
+
+/// Returns the `(major, minor)` version of PSCI the firmware implements.
+pub fn version() -> Result<(u16, u16), PsciErr> {
+    let v = call(PSCI_VERSION, 0, 0, 0)?;
+    if v < 0 {
+        return Err(PsciErr::from_code(v));
+    }
+    Ok(((v >> 16) as u16, v as u16))
+}
+
+/// Turns the whole system off. Only returns if the firmware refused.
+pub fn system_off() -> PsciErr {
+    match call(SYSTEM_OFF, 0, 0, 0) {
+        Ok(code) => PsciErr::from_code(code),
+        Err(e) => e,
+    }
+}
+
+/// Resets the whole system. Only returns if the firmware refused.
+pub fn system_reset() -> PsciErr {
+    match call(SYSTEM_RESET, 0, 0, 0) {
+        Ok(code) => PsciErr::from_code(code),
+        Err(e) => e,
+    }
+}
+
+/// Reports whether the core with affinity `mpidr` is on.
+pub fn affinity_info(mpidr: u64) -> Result<AffinityState, PsciErr> {
+    // Lowest affinity level 0, the core itself.
+    match call(AFFINITY_INFO, mpidr, 0, 0)? {
+        0 => Ok(AffinityState::On),
+        1 => Ok(AffinityState::Off),
+        2 => Ok(AffinityState::OnPending),
+        code => Err(PsciErr::from_code(code)),
+    }
+}
+
+/// Suspends the calling core in `power_state`. Standby states return here once an interrupt
+/// arrives, powerdown states instead resume at `entry` with the MMU off and `context` in x0.
+pub fn cpu_suspend(power_state: u32, entry: usize, context: u64) -> Result<(), PsciErr> {
+    call_status(CPU_SUSPEND, power_state as u64, entry as u64, context)
+}
+
+/// Powers on the core with affinity `mpidr`, which starts executing at the physical address
+/// `entry` with the MMU off and `context` in x0.
+pub fn cpu_on(mpidr: u64, entry: usize, context: u64) -> Result<(), PsciErr> {
+    call_status(CPU_ON, mpidr, entry as u64, context)
+}
@@ -0,0 +1,525 @@
+use crate::exceptions::without_interrupts;
+use crate::timer::{self, Instant};
+use crate::{frames, gic, mmu};
+use alloc::{boxed::Box, collections::BTreeMap, collections::VecDeque, vec::Vec};
+use core::arch::global_asm;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+
+#[cfg(target_arch = "aarch64")]
+global_asm!(include_str!("switch.S"));
+
+extern "C" {
+    fn cpu_switch(prev: *mut Context, next: *const Context);
+    fn thread_trampoline();
+}
+
+/// Number of frames in every kernel thread's stack.
+const STACK_FRAMES: usize = 4;
+/// Number of timer ticks a thread may run before it is preempted.
+const TIMESLICE_TICKS: u32 = 5;
+/// SPSR for a new thread: EL1h with all exceptions unmasked.
+const SPSR_EL1H: u64 = 0b0101;
+
+/// State saved across `cpu_switch`. The layout must match `switch.S`.
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct Context {
+    /// x19 through x29
+    callee_saved: [u64; 11],
+    lr: u64,
+    sp: u64,
+    elr: u64,
+    spsr: u64,
+    /// d8 through d15, the low halves of the callee-saved SIMD registers.
+    fp_callee_saved: [u64; 8],
+}
+
+// switch.S stores d8-d15 right after SPSR_EL1, at byte 120.
+const _: () = assert!(core::mem::size_of::<Context>() == 184);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u32);
+
+impl ThreadId {
+    pub fn as_u32(self) -> u32 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    Ready,
+    Blocked,
+    Sleeping(Instant),
+    Exited,
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    state: State,
+    ctx: Context,
+    /// Physical address of the first frame of this thread's stack. The boot thread runs on the
+    /// stack set up by `boot.S` and has none.
+    stack: Option<usize>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    /// Thread waiting in `join` for this one to exit.
+    joiner: Option<ThreadId>,
+    /// Whether nobody will join this thread, so it is reaped as soon as it exits.
+    detached: bool,
+    /// Root table of the address space this thread runs in, or `None` for the kernel's.
+    address_space: Option<*mut mmu::Table>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: &'static str,
+    pub state: State,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpawnErr {
+    NotInitialized,
+    NoStack,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinErr {
+    NoSuchThread,
+    JoinSelf,
+    AlreadyJoined,
+}
+
+struct Scheduler {
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    run_queue: VecDeque<ThreadId>,
+    /// Exited detached threads whose stacks can be freed once we are off of them.
+    zombies: Vec<ThreadId>,
+    current: ThreadId,
+    idle: ThreadId,
+    next_id: u32,
+    slice_left: u32,
+}
+
+static mut SCHED: Option<Scheduler> = None;
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+/// Runs `f` on the scheduler with interrupts masked.
+fn with<R>(f: impl FnOnce(&mut Scheduler) -> R) -> Option<R> {
+    without_interrupts(|| unsafe { SCHED.as_mut().map(f) })
+}
+
+impl Scheduler {
+    fn thread(&mut self, id: ThreadId) -> &mut Thread {
+        self.threads.get_mut(&id).expect("Unknown thread")
+    }
+
+    fn current(&mut self) -> &mut Thread {
+        let id = self.current;
+        self.thread(id)
+    }
+
+    fn create(
+        &mut self,
+        name: &'static str,
+        entry: Box<dyn FnOnce() + Send>,
+    ) -> Result<ThreadId, SpawnErr> {
+        let stack = frames::alloc_contiguous(STACK_FRAMES).ok_or(SpawnErr::NoStack)?;
+        let id = ThreadId(self.next_id);
+        self.next_id += 1;
+        let ctx = Context {
+            lr: thread_trampoline as usize as u64,
+            sp: (stack + STACK_FRAMES * mmu::PAGE_SIZE) as u64,
+            elr: thread_start as usize as u64,
+            spsr: SPSR_EL1H,
+            ..Context::default()
+        };
+        self.threads.insert(
+            id,
+            Box::new(Thread {
+                id,
+                name,
+                state: State::Ready,
+                ctx,
+                stack: Some(stack),
+                entry: Some(entry),
+                joiner: None,
+                detached: false,
+                address_space: None,
+            }),
+        );
+        // Room for every thread to be queued at once, so waking one from an interrupt never
+        // has to allocate.
+        let queued = self.run_queue.len();
+        self.run_queue
+            .reserve(self.threads.len().saturating_sub(queued));
+        Ok(id)
+    }
+
+    fn make_ready(&mut self, id: ThreadId) {
+        let thread = self.thread(id);
+        if matches!(thread.state, State::Blocked | State::Sleeping(_)) {
+            thread.state = State::Ready;
+            self.run_queue.push_back(id);
+            if self.current == self.idle {
+                NEED_RESCHED.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    fn reap(&mut self, id: ThreadId) {
+        if let Some(thread) = self.threads.remove(&id) {
+            if let Some(stack) = thread.stack {
+                frames::free(stack, STACK_FRAMES);
+            }
+        }
+    }
+}
+
+/// Picks the next thread to run and switches to it. Interrupts must be masked.
+unsafe fn schedule() {
+    let sched = SCHED.as_mut().expect("Scheduler not initialized");
+    NEED_RESCHED.store(false, Ordering::Relaxed);
+    let prev_id = sched.current;
+    for zombie in core::mem::take(&mut sched.zombies) {
+        if zombie == prev_id {
+            sched.zombies.push(zombie);
+        } else {
+            sched.reap(zombie);
+        }
+    }
+    let prev = sched.current();
+    if prev.state == State::Running {
+        prev.state = State::Ready;
+        if prev_id != sched.idle {
+            sched.run_queue.push_back(prev_id);
+        }
+    }
+    let next_id = sched.run_queue.pop_front().unwrap_or(sched.idle);
+    sched.slice_left = TIMESLICE_TICKS;
+    sched.thread(next_id).state = State::Running;
+    if next_id == prev_id {
+        return;
+    }
+    sched.current = next_id;
+    let prev: *mut Thread = &mut **sched.threads.get_mut(&prev_id).unwrap();
+    let next: *const Thread = &**sched.threads.get(&next_id).unwrap();
+    if (*prev).address_space != (*next).address_space {
+        activate((*next).address_space);
+    }
+    cpu_switch(&mut (*prev).ctx, &(*next).ctx);
+}
+
+unsafe fn activate(root: Option<*mut mmu::Table>) {
+    match root {
+        Some(root) => mmu::AddressSpace::from_root(root).activate(),
+        None => {
+            if let Some(kernel) = mmu::kernel_space() {
+                kernel.activate();
+            }
+        }
+    }
+}
+
+/// Where every new thread begins, with interrupts unmasked.
+extern "C" fn thread_start() -> ! {
+    let entry = with(|sched| sched.current().entry.take()).flatten();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit()
+}
+
+fn idle() {
+    loop {
+        gic::wait_for_interrupt();
+    }
+}
+
+fn tick(now: Instant) {
+    let sched = if let Some(sched) = unsafe { SCHED.as_mut() } {
+        sched
+    } else {
+        return;
+    };
+    // Woken in place, as allocating from an interrupt could fail with nowhere to report it.
+    let mut woken = false;
+    for thread in sched.threads.values_mut() {
+        if matches!(thread.state, State::Sleeping(deadline) if deadline <= now) {
+            thread.state = State::Ready;
+            sched.run_queue.push_back(thread.id);
+            woken = true;
+        }
+    }
+    if woken && sched.current == sched.idle {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+    sched.slice_left = sched.slice_left.saturating_sub(1);
+    if sched.slice_left == 0 && !sched.run_queue.is_empty() {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Turns the running code into the boot thread and starts the idle thread and timeslicing.
+pub fn init() -> Result<(), SpawnErr> {
+    let boot = ThreadId(0);
+    let mut sched = Scheduler {
+        threads: BTreeMap::new(),
+        run_queue: VecDeque::new(),
+        zombies: Vec::new(),
+        current: boot,
+        idle: boot,
+        next_id: 1,
+        slice_left: TIMESLICE_TICKS,
+    };
+    sched.threads.insert(
+        boot,
+        Box::new(Thread {
+            id: boot,
+            name: "main",
+            state: State::Running,
+            ctx: Context::default(),
+            stack: None,
+            entry: None,
+            joiner: None,
+            detached: true,
+            address_space: None,
+        }),
+    );
+    sched.idle = sched.create("idle", Box::new(idle))?;
+    without_interrupts(|| unsafe { SCHED = Some(sched) });
+    timer::register_tick_callback(tick).map_err(|_| SpawnErr::NotInitialized)
+}
+
+pub fn is_running() -> bool {
+    unsafe { SCHED.is_some() }
+}
+
+/// Called on the way out of an IRQ, switching threads if the timeslice ran out or a thread
+/// woke up while idling.
+pub(crate) fn preempt() {
+    if is_running() && NEED_RESCHED.load(Ordering::Relaxed) {
+        unsafe { schedule() };
+    }
+}
+
+/// A handle to wait for a spawned thread. Dropping it detaches the thread.
+#[derive(Debug)]
+pub struct JoinHandle(ThreadId);
+
+impl JoinHandle {
+    pub fn id(&self) -> ThreadId {
+        self.0
+    }
+
+    pub fn join(self) -> Result<(), JoinErr> {
+        let id = self.0;
+        core::mem::forget(self);
+        join(id)
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        let id = self.0;
+        with(|sched| match sched.threads.get_mut(&id) {
+            Some(thread) if thread.state == State::Exited => sched.reap(id),
+            Some(thread) => thread.detached = true,
+            None => {}
+        });
+    }
+}
+
+/// Starts a new kernel thread running `f`.
+pub fn spawn<F>(name: &'static str, f: F) -> Result<JoinHandle, SpawnErr>
+where
+    F: FnOnce() + Send + 'static,
+{
+    with(|sched| {
+        let id = sched.create(name, Box::new(f))?;
+        sched.run_queue.push_back(id);
+        Ok(JoinHandle(id))
+    })
+    .unwrap_or(Err(SpawnErr::NotInitialized))
+}
+
+/// Blocks until the thread `id` exits, then frees it.
+fn join(id: ThreadId) -> Result<(), JoinErr> {
+    without_interrupts(|| unsafe {
+        let sched = SCHED.as_mut().ok_or(JoinErr::NoSuchThread)?;
+        if id == sched.current {
+            return Err(JoinErr::JoinSelf);
+        }
+        loop {
+            let current = sched.current;
+            let thread = sched.threads.get_mut(&id).ok_or(JoinErr::NoSuchThread)?;
+            if thread.state == State::Exited {
+                sched.reap(id);
+                return Ok(());
+            }
+            match thread.joiner {
+                Some(joiner) if joiner != current => return Err(JoinErr::AlreadyJoined),
+                _ => thread.joiner = Some(current),
+            }
+            sched.current().state = State::Blocked;
+            schedule();
+        }
+    })
+}
+
+/// Gives up the rest of this thread's timeslice.
+pub fn yield_now() {
+    if is_running() {
+        without_interrupts(|| unsafe { schedule() });
+    }
+}
+
+/// Ends the calling thread.
+pub fn exit() -> ! {
+    without_interrupts(|| unsafe {
+        let sched = SCHED.as_mut().expect("Scheduler not initialized");
+        let current = sched.current();
+        current.state = State::Exited;
+        let (id, joiner, detached) = (current.id, current.joiner, current.detached);
+        if let Some(joiner) = joiner {
+            sched.make_ready(joiner);
+        }
+        if detached {
+            sched.zombies.push(id);
+        }
+        schedule();
+    });
+    unreachable!("Exited thread was rescheduled");
+}
+
+/// Marks the calling thread blocked and switches away until someone calls `wake` on it.
+/// Interrupts must be masked so that a wakeup cannot be missed in between checking a condition
+/// and blocking.
+pub unsafe fn block_current() {
+    if let Some(sched) = SCHED.as_mut() {
+        sched.current().state = State::Blocked;
+        schedule();
+    }
+}
+
+/// Makes a blocked or sleeping thread runnable.
+pub fn wake(id: ThreadId) {
+    with(|sched| {
+        if sched.threads.contains_key(&id) {
+            sched.make_ready(id);
+        }
+    });
+}
+
+/// Switches the calling thread to the address space rooted at `root`, or back to the kernel's
+/// for `None`. The tables must stay alive for as long as the thread uses them.
+pub unsafe fn set_address_space(root: Option<*mut mmu::Table>) {
+    without_interrupts(|| {
+        if let Some(sched) = SCHED.as_mut() {
+            sched.current().address_space = root;
+        }
+        activate(root);
+    });
+}
+
+pub fn current() -> Option<ThreadId> {
+    with(|sched| sched.current)
+}
+
+/// Sleeps the calling thread until `deadline`, letting other threads run.
+pub fn sleep_until(deadline: Instant) {
+    if !is_running() {
+        while Instant::now() < deadline {
+            gic::wait_for_interrupt();
+        }
+        return;
+    }
+    without_interrupts(|| unsafe {
+        if let Some(sched) = SCHED.as_mut() {
+            sched.current().state = State::Sleeping(deadline);
+            schedule();
+        }
+    });
+}
+
+pub fn sleep(d: Duration) {
+    sleep_until(Instant::now() + d);
+}
+
+/// Lists every thread the scheduler knows about.
+pub fn threads() -> Vec<ThreadInfo> {
+    with(|sched| {
+        sched
+            .threads
+            .values()
+            .map(|t| ThreadInfo {
+                id: t.id,
+                name: t.name,
+                state: t.state,
+            })
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// A lock which yields to other threads while it is contended.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            locked: AtomicBool::new(false),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| MutexGuard { mutex: self })
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            yield_now();
+        }
+    }
+}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,201 @@
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+
+/// A statement of a shell script. Lines are kept as written, variables are expanded when they
+/// run so that loops see the current values.
+#[derive(Debug)]
+pub enum Stmt<'a> {
+    Line(&'a str),
+    /// Runs `then` if `cond` succeeds, or if the last command did when there is no `cond`.
+    If {
+        cond: Option<&'a str>,
+        then: Vec<Stmt<'a>>,
+        otherwise: Vec<Stmt<'a>>,
+    },
+    /// Runs `body` once for each word of `items`, with `var` set to it.
+    For {
+        var: &'a str,
+        items: &'a str,
+        body: Vec<Stmt<'a>>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrKind {
+    UnexpectedElse,
+    UnexpectedEnd,
+    /// An `if` or `for` was never closed, the script may just be incomplete.
+    MissingEnd,
+    /// A `for` not of the form `for VAR in WORDS...`.
+    BadFor,
+    /// Words after an `else` or `end`, which must be alone on their line.
+    TrailingWords,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseErr {
+    /// One based line number.
+    pub line: usize,
+    pub kind: ParseErrKind,
+}
+
+/// What ended a block.
+enum Terminator {
+    Else(usize),
+    End(usize),
+    Eof,
+}
+
+/// Cuts a `#` comment off the end of `line`. Only a `#` starting a word counts.
+fn strip_comment(line: &str) -> &str {
+    let mut prev_space = true;
+    for (i, c) in line.char_indices() {
+        if c == '#' && prev_space {
+            return &line[..i];
+        }
+        prev_space = c.is_whitespace();
+    }
+    line
+}
+
+/// Splits off the first word, returning it and the trimmed remainder.
+fn first_word(line: &str) -> (&str, &str) {
+    match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim_start()),
+        None => (line, ""),
+    }
+}
+
+/// Parses statements up to the `else` or `end` closing the current block.
+fn block<'a>(
+    lines: &mut impl Iterator<Item = (usize, &'a str)>,
+) -> Result<(Vec<Stmt<'a>>, Terminator), ParseErr> {
+    let mut stmts = Vec::new();
+    while let Some((number, line)) = lines.next() {
+        let (keyword, rest) = first_word(line);
+        if (keyword == "else" || keyword == "end") && !rest.is_empty() {
+            return Err(ParseErr {
+                line: number,
+                kind: ParseErrKind::TrailingWords,
+            });
+        }
+        match keyword {
+            "else" => return Ok((stmts, Terminator::Else(number))),
+            "end" => return Ok((stmts, Terminator::End(number))),
+            "if" => {
+                let missing_end = ParseErr {
+                    line: number,
+                    kind: ParseErrKind::MissingEnd,
+                };
+                let (then, term) = block(lines)?;
+                let otherwise = match term {
+                    Terminator::End(_) => Vec::new(),
+                    Terminator::Else(_) => match block(lines)? {
+                        (otherwise, Terminator::End(_)) => otherwise,
+                        (_, Terminator::Else(line)) => {
+                            return Err(ParseErr {
+                                line,
+                                kind: ParseErrKind::UnexpectedElse,
+                            })
+                        }
+                        (_, Terminator::Eof) => return Err(missing_end),
+                    },
+                    Terminator::Eof => return Err(missing_end),
+                };
+                stmts.push(Stmt::If {
+                    cond: if rest.is_empty() { None } else { Some(rest) },
+                    then,
+                    otherwise,
+                });
+            }
+            "for" => {
+                let (var, rest) = first_word(rest);
+                let (keyword, items) = first_word(rest);
+                if var.is_empty() || keyword != "in" {
+                    return Err(ParseErr {
+                        line: number,
+                        kind: ParseErrKind::BadFor,
+                    });
+                }
+                let body = match block(lines)? {
+                    (body, Terminator::End(_)) => body,
+                    (_, Terminator::Else(line)) => {
+                        return Err(ParseErr {
+                            line,
+                            kind: ParseErrKind::UnexpectedElse,
+                        })
+                    }
+                    (_, Terminator::Eof) => {
+                        return Err(ParseErr {
+                            line: number,
+                            kind: ParseErrKind::MissingEnd,
+                        })
+                    }
+                };
+                stmts.push(Stmt::For { var, items, body });
+            }
+            _ => stmts.push(Stmt::Line(line)),
+        }
+    }
+    Ok((stmts, Terminator::Eof))
+}
+
+/// Parses a whole script. Blank lines and comments are dropped.
+pub fn parse(src: &str) -> Result<Vec<Stmt<'_>>, ParseErr> {
+    let mut lines = src
+        .lines()
+        .enumerate()
+        .map(|(i, line)| (i + 1, strip_comment(line).trim()))
+        .filter(|(_, line)| !line.is_empty());
+    match block(&mut lines)? {
+        (stmts, Terminator::Eof) => Ok(stmts),
+        (_, Terminator::Else(line)) => Err(ParseErr {
+            line,
+            kind: ParseErrKind::UnexpectedElse,
+        }),
+        (_, Terminator::End(line)) => Err(ParseErr {
+            line,
+            kind: ParseErrKind::UnexpectedEnd,
+        }),
+    }
+}
+
+pub fn is_var_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Substitutes `$NAME`, `${NAME}` and `$?`, the status of the last command. Unset variables
+/// expand to nothing and comments are removed.
+pub fn expand(line: &str, vars: &BTreeMap<String, String>, status: i32) -> String {
+    let line = strip_comment(line);
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(i) = rest.find('$') {
+        out.push_str(&rest[..i]);
+        rest = &rest[i + 1..];
+        if let Some(after) = rest.strip_prefix('?') {
+            out.push_str(&alloc::format!("{}", status));
+            rest = after;
+            continue;
+        }
+        let (name, after) = match rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+            Some((name, after)) => (name, after),
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        if name.is_empty() {
+            // A lone `$` or `${}` stays as it is.
+            out.push('$');
+            continue;
+        }
+        if let Some(value) = vars.get(name) {
+            out.push_str(value);
+        }
+        rest = after;
+    }
+    out.push_str(rest);
+    out
+}
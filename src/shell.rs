@@ -0,0 +1,953 @@
+use crate::files::{self, FileErr};
+use crate::fs::{self, FileDescriptor, FileMode};
+use crate::line_editor::{CompletionKind, LineEditor};
+use crate::log::{self, LevelFilter};
+use crate::path::Path;
+use crate::process::KernelFs;
+use crate::script::{self, ParseErrKind, Stmt};
+use crate::uart::UART;
+use crate::virtio::VirtIOEntropy;
+use crate::{elf, frames, gdb, heap, mmu, process, psci, sched, smp, timer};
+use alloc::{collections::BTreeMap, format, string::String, vec::Vec};
+use core::fmt::{self, Write};
+use core::str::FromStr;
+use core::time::Duration;
+
+/// Everything a command can reach while it runs.
+pub struct Context<'a> {
+    pub uart: UART,
+    pub fs: &'static sched::Mutex<KernelFs>,
+    pub entropy: VirtIOEntropy<'a>,
+    /// Kept open so that paths can always be opened from the root.
+    pub root: FileDescriptor,
+    pub cwd: Path,
+    /// Shell variables, for `$NAME` expansion.
+    pub vars: BTreeMap<String, String>,
+    /// 0 if the last command succeeded, 1 if it failed.
+    pub status: i32,
+    exit: bool,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(
+        uart: UART,
+        fs: &'static sched::Mutex<KernelFs>,
+        entropy: VirtIOEntropy<'a>,
+    ) -> Result<Self, ()> {
+        let root = fs.lock().root_dir(FileMode::RW)?;
+        Ok(Context {
+            uart,
+            fs,
+            entropy,
+            root,
+            cwd: Path::root(),
+            vars: BTreeMap::new(),
+            status: 0,
+            exit: false,
+        })
+    }
+
+    /// Resolves a path typed by the user against the working directory.
+    pub fn resolve(&self, path: &str) -> Path {
+        self.cwd.join(&Path::parse(path))
+    }
+
+    /// Makes the shell stop once the current command returns.
+    pub fn exit(&mut self) {
+        self.exit = true;
+    }
+}
+
+#[derive(Debug)]
+pub enum ShellErr {
+    /// The arguments did not match the command's usage.
+    Usage,
+    /// The named argument was there but could not be parsed.
+    BadArgument(&'static str),
+    UnknownCommand,
+    File(FileErr),
+    /// Anything else, already described.
+    Failed(String),
+}
+
+impl From<FileErr> for ShellErr {
+    fn from(e: FileErr) -> Self {
+        ShellErr::File(e)
+    }
+}
+
+/// Errors with nothing more to them than their `Debug` output.
+macro_rules! described_err {
+    ($($err:ty),* $(,)?) => {
+        $(
+            impl From<$err> for ShellErr {
+                fn from(e: $err) -> Self {
+                    ShellErr::Failed(format!("{:?}", e))
+                }
+            }
+        )*
+    };
+}
+
+described_err!(
+    fs::OpenErr,
+    fs::ReadErr,
+    fs::WriteErr,
+    fs::SeekErr,
+    fs::CloseErr,
+    fs::FlushErr,
+    elf::ElfErr,
+    process::WaitErr,
+    psci::PsciErr,
+);
+
+impl fmt::Display for ShellErr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShellErr::Usage => f.write_str("wrong arguments"),
+            ShellErr::BadArgument(name) => write!(f, "invalid {}", name),
+            ShellErr::UnknownCommand => f.write_str("unknown command"),
+            ShellErr::File(e) => write!(f, "{:?}", e),
+            ShellErr::Failed(e) => f.write_str(e),
+        }
+    }
+}
+
+/// The words after a command's name.
+pub struct Args<'a> {
+    words: &'a [&'a str],
+    next: usize,
+}
+
+impl<'a> Iterator for Args<'a> {
+    type Item = &'a str;
+    fn next(&mut self) -> Option<&'a str> {
+        let word = self.words.get(self.next)?;
+        self.next += 1;
+        Some(word)
+    }
+}
+
+impl<'a> Args<'a> {
+    pub fn new(words: &'a [&'a str]) -> Self {
+        Args { words, next: 0 }
+    }
+
+    pub fn required(&mut self) -> Result<&'a str, ShellErr> {
+        self.next().ok_or(ShellErr::Usage)
+    }
+
+    pub fn parse<T: FromStr>(&mut self, name: &'static str) -> Result<T, ShellErr> {
+        self.required()?
+            .parse()
+            .map_err(|_| ShellErr::BadArgument(name))
+    }
+
+    pub fn parse_or<T: FromStr>(&mut self, name: &'static str, default: T) -> Result<T, ShellErr> {
+        match self.next() {
+            Some(word) => word.parse().map_err(|_| ShellErr::BadArgument(name)),
+            None => Ok(default),
+        }
+    }
+
+    pub fn fd(&mut self) -> Result<FileDescriptor, ShellErr> {
+        self.parse::<u32>("file descriptor")
+            .map(FileDescriptor::from)
+    }
+
+    pub fn path(&mut self, ctx: &Context) -> Result<Path, ShellErr> {
+        self.required().map(|path| ctx.resolve(path))
+    }
+
+    /// Everything not consumed yet.
+    pub fn rest(&mut self) -> &'a [&'a str] {
+        let rest = &self.words[self.next..];
+        self.next = self.words.len();
+        rest
+    }
+
+    /// Fails if there are arguments left over.
+    pub fn finish(&self) -> Result<(), ShellErr> {
+        if self.next < self.words.len() {
+            return Err(ShellErr::Usage);
+        }
+        Ok(())
+    }
+}
+
+pub trait Command {
+    fn name(&self) -> &'static str;
+    /// The arguments, as shown after the name in usage messages.
+    fn usage(&self) -> &'static str;
+    /// A one line description for `help`.
+    fn help(&self) -> &'static str;
+    fn run(&self, ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr>;
+}
+
+/// A command implemented by a plain function.
+pub struct Builtin {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub run: fn(&mut Context, &mut Args) -> Result<(), ShellErr>,
+}
+
+impl Command for Builtin {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+    fn usage(&self) -> &'static str {
+        self.usage
+    }
+    fn help(&self) -> &'static str {
+        self.help
+    }
+    fn run(&self, ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+        (self.run)(ctx, args)
+    }
+}
+
+/// Commands the shell runs itself, since they need more than a `Context`, as
+/// `(name, usage, help)`.
+const SHELL_COMMANDS: &[(&str, &str, &str)] = &[
+    ("help", "[command]", "Describes a command"),
+    ("source", "<path>", "Runs a script"),
+    ("sh", "<path>", "Runs a script"),
+];
+
+/// How deeply scripts may source other scripts.
+const MAX_SCRIPT_DEPTH: usize = 8;
+
+pub struct Shell<'a> {
+    pub ctx: Context<'a>,
+    commands: Vec<&'a dyn Command>,
+    editor: LineEditor,
+    /// Number of scripts currently running.
+    depth: usize,
+}
+
+impl<'a> Shell<'a> {
+    /// A shell with every builtin command registered.
+    pub fn new(ctx: Context<'a>) -> Self {
+        let mut shell = Shell {
+            ctx,
+            commands: Vec::new(),
+            editor: LineEditor::new(),
+            depth: 0,
+        };
+        for builtin in BUILTINS {
+            shell.register(builtin);
+        }
+        shell
+    }
+
+    /// Adds `command`, replacing any command with the same name.
+    pub fn register(&mut self, command: &'a dyn Command) {
+        self.commands.retain(|c| c.name() != command.name());
+        self.commands.push(command);
+    }
+
+    fn command(&self, name: &str) -> Option<&'a dyn Command> {
+        self.commands.iter().copied().find(|c| c.name() == name)
+    }
+
+    /// The usage and help text of any command.
+    fn describe(&self, name: &str) -> Option<(&'static str, &'static str)> {
+        match self.command(name) {
+            Some(command) => Some((command.usage(), command.help())),
+            None => SHELL_COMMANDS
+                .iter()
+                .find(|(n, _, _)| *n == name)
+                .map(|&(_, usage, help)| (usage, help)),
+        }
+    }
+
+    /// Reads and runs commands until one of them asks to exit.
+    pub fn run(&mut self) {
+        while !self.ctx.exit {
+            if let Some(input) = self.read_input() {
+                if let Ok(stmts) = script::parse(&input) {
+                    self.run_stmts(&stmts);
+                }
+            }
+        }
+    }
+
+    /// Reads a line, or several if it opens an `if` or `for`, which only run once their block
+    /// is closed.
+    fn read_input(&mut self) -> Option<String> {
+        let mut input = String::new();
+        let mut prompt = "$> ";
+        loop {
+            let line = self.read_line(prompt);
+            match core::str::from_utf8(&line) {
+                Ok(line) => input.push_str(line),
+                Err(_) => {
+                    let _ = writeln!(self.ctx.uart, "Input is not valid UTF-8");
+                    return None;
+                }
+            }
+            input.push('\n');
+            match script::parse(&input) {
+                Ok(_) => return Some(input),
+                Err(e) if e.kind == ParseErrKind::MissingEnd => prompt = "> ",
+                Err(e) => {
+                    let _ = writeln!(self.ctx.uart, "{:?} on line {}", e.kind, e.line);
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn read_line(&mut self, prompt: &str) -> Vec<u8> {
+        let Shell {
+            ctx,
+            commands,
+            editor,
+            ..
+        } = self;
+        let Context {
+            uart,
+            fs,
+            root,
+            cwd,
+            ..
+        } = ctx;
+        editor.read_line(uart, prompt, &mut |word, kind| match kind {
+            CompletionKind::Command => SHELL_COMMANDS
+                .iter()
+                .map(|(name, _, _)| *name)
+                .chain(commands.iter().map(|c| c.name()))
+                .map(String::from)
+                .collect(),
+            CompletionKind::Argument => {
+                // Complete the last component, listing the directory named by the rest.
+                let split = word.rfind('/').map_or(0, |i| i + 1);
+                let (dir_part, _) = word.split_at(split);
+                let dir = cwd.join(&Path::parse(dir_part));
+                let names = files::list(&mut fs.lock(), *root, &dir).unwrap_or_default();
+                names
+                    .into_iter()
+                    .filter(|name| name != "." && name != "..")
+                    .map(|name| format!("{}{}", dir_part, name))
+                    .collect()
+            }
+        })
+    }
+
+    fn run_stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            if self.ctx.exit {
+                return;
+            }
+            match stmt {
+                Stmt::Line(line) => {
+                    let _ = self.execute(line);
+                }
+                Stmt::If {
+                    cond,
+                    then,
+                    otherwise,
+                } => {
+                    let ok = match cond {
+                        Some(cond) => self.execute(cond).is_ok(),
+                        None => self.ctx.status == 0,
+                    };
+                    self.run_stmts(if ok { then } else { otherwise });
+                }
+                Stmt::For { var, items, body } => {
+                    let items = script::expand(items, &self.ctx.vars, self.ctx.status);
+                    for item in items.split_whitespace() {
+                        self.ctx.vars.insert(String::from(*var), String::from(item));
+                        self.run_stmts(body);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs the script at `path`. Commands in it failing does not stop it, only a script that
+    /// cannot be read or parsed is an error.
+    pub fn source(&mut self, path: &Path) -> Result<(), ShellErr> {
+        if self.depth == MAX_SCRIPT_DEPTH {
+            return Err(ShellErr::Failed(String::from("scripts nested too deeply")));
+        }
+        let src = files::read(&mut self.ctx.fs.lock(), self.ctx.root, path)?;
+        let src = core::str::from_utf8(&src)
+            .map_err(|_| ShellErr::Failed(format!("{} is not valid UTF-8", path)))?;
+        let stmts = script::parse(src)
+            .map_err(|e| ShellErr::Failed(format!("{}:{}: {:?}", path, e.line, e.kind)))?;
+        self.depth += 1;
+        self.run_stmts(&stmts);
+        self.depth -= 1;
+        Ok(())
+    }
+
+    /// Expands and runs a single line, reporting any error on the console and recording the
+    /// status for `$?`.
+    pub fn execute(&mut self, line: &str) -> Result<(), ShellErr> {
+        let line = script::expand(line, &self.ctx.vars, self.ctx.status);
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (&name, rest) = if let Some(split) = words.split_first() {
+            split
+        } else {
+            return Ok(());
+        };
+        let mut args = Args::new(rest);
+        let result = match name {
+            "help" => self.help(&mut args),
+            "source" | "sh" => match (args.next(), args.next()) {
+                (Some(path), None) => {
+                    let path = self.ctx.resolve(path);
+                    self.source(&path)
+                }
+                _ => Err(ShellErr::Usage),
+            },
+            name => match self.command(name) {
+                Some(command) => command.run(&mut self.ctx, &mut args),
+                None => Err(ShellErr::UnknownCommand),
+            },
+        };
+        match &result {
+            Ok(()) => {}
+            Err(ShellErr::Usage) => {
+                let usage = self.describe(name).map_or("", |(usage, _)| usage);
+                let _ = writeln!(self.ctx.uart, "Usage: {} {}", name, usage);
+            }
+            Err(ShellErr::UnknownCommand) => {
+                let _ = writeln!(self.ctx.uart, "Unknown command \"{}\"", name);
+            }
+            Err(e) => {
+                let _ = writeln!(self.ctx.uart, "{}: {}", name, e);
+            }
+        }
+        self.ctx.status = if result.is_ok() { 0 } else { 1 };
+        result
+    }
+
+    fn help(&mut self, args: &mut Args) -> Result<(), ShellErr> {
+        let name = if let Some(name) = args.next() {
+            name
+        } else {
+            let uart = &mut self.ctx.uart;
+            for command in &self.commands {
+                let _ = writeln!(uart, "{:<12} {}", command.name(), command.help());
+            }
+            for (name, _, help) in SHELL_COMMANDS {
+                let _ = writeln!(uart, "{:<12} {}", name, help);
+            }
+            return Ok(());
+        };
+        let (usage, help) = self
+            .describe(name)
+            .ok_or_else(|| ShellErr::Failed(format!("unknown command \"{}\"", name)))?;
+        let _ = writeln!(self.ctx.uart, "Usage: {} {}", name, usage);
+        let _ = writeln!(self.ctx.uart, "{}", help);
+        Ok(())
+    }
+}
+
+macro_rules! builtin {
+    ($name:literal, $usage:literal, $help:literal, $run:expr) => {
+        Builtin {
+            name: $name,
+            usage: $usage,
+            help: $help,
+            run: $run,
+        }
+    };
+}
+
+static BUILTINS: &[Builtin] = &[
+    builtin!(
+        "set",
+        "[name [value...]]",
+        "Sets a variable, or lists them all",
+        set
+    ),
+    builtin!("unset", "<name>", "Removes a variable", unset),
+    builtin!("ls", "[path]", "Lists a directory", ls),
+    builtin!("cd", "[path]", "Changes the working directory", cd),
+    builtin!("pwd", "", "Prints the working directory", pwd),
+    builtin!("mkdir", "<path>", "Makes a directory", mkdir),
+    builtin!("rmdir", "<path>", "Removes an empty directory", rmdir),
+    builtin!("cat", "<path>", "Prints a file", cat),
+    builtin!("hexdump", "<path>", "Prints a file as hex", hexdump),
+    builtin!(
+        "echo",
+        "[text...] [> or >> <path>]",
+        "Prints text, or writes or appends it to a file",
+        echo
+    ),
+    builtin!("cp", "<from> <to>", "Copies a file", cp),
+    builtin!("mv", "<from> <to>", "Moves a file", mv),
+    builtin!("rm", "<path...>", "Removes files", rm),
+    builtin!("touch", "<path...>", "Creates empty files", touch),
+    builtin!("stat", "<path>", "Shows the size and kind of a file", stat),
+    builtin!(
+        "open",
+        "<path> [mode=RW]",
+        "Opens a file and prints its descriptor",
+        open
+    ),
+    builtin!(
+        "fread",
+        "<fd> [len=512]",
+        "Prints bytes read from a descriptor",
+        fread
+    ),
+    builtin!(
+        "fwriterand",
+        "<fd> [len=512]",
+        "Writes random bytes to a descriptor",
+        fwriterand
+    ),
+    builtin!(
+        "fseek",
+        "<fd> [offset=0]",
+        "Moves a descriptor's offset",
+        fseek
+    ),
+    builtin!("fclose", "<fd>", "Closes a descriptor", fclose),
+    builtin!("fs_stat", "", "Shows file system statistics", fs_stat),
+    builtin!(
+        "exec",
+        "<path> [args...]",
+        "Runs a program and waits for it",
+        exec
+    ),
+    builtin!("rand", "", "Prints random bytes", rand),
+    builtin!("uptime", "", "Shows the time since boot", uptime),
+    builtin!("dmesg", "", "Prints the kernel log", dmesg),
+    builtin!(
+        "loglevel",
+        "[module] [off|error|warn|info|debug|trace|default]",
+        "Shows or sets which messages are logged, for everything or for one module",
+        loglevel
+    ),
+    builtin!("sleep", "<ms>", "Waits for a number of milliseconds", sleep),
+    builtin!("mem", "", "Shows memory usage", mem),
+    builtin!("ps", "", "Lists threads", ps),
+    builtin!("cpus", "", "Lists cores and their state", cpus),
+    builtin!(
+        "debug",
+        "",
+        "Stops in the GDB stub until the debugger continues",
+        debug
+    ),
+    builtin!("exit", "", "Powers off", poweroff),
+    builtin!("poweroff", "", "Powers off", poweroff),
+    builtin!("reboot", "", "Resets the system", reboot),
+];
+
+fn set(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let name = if let Some(name) = args.next() {
+        name
+    } else {
+        for (name, value) in &ctx.vars {
+            let _ = writeln!(ctx.uart, "{}={}", name, value);
+        }
+        return Ok(());
+    };
+    if !script::is_var_name(name) {
+        return Err(ShellErr::BadArgument("name"));
+    }
+    let value = args.rest().join(" ");
+    ctx.vars.insert(String::from(name), value);
+    Ok(())
+}
+
+fn unset(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let name = args.required()?;
+    args.finish()?;
+    ctx.vars.remove(name);
+    Ok(())
+}
+
+fn ls(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let path = match args.next() {
+        Some(path) => ctx.resolve(path),
+        None => ctx.cwd.clone(),
+    };
+    args.finish()?;
+    let names = files::list(&mut ctx.fs.lock(), ctx.root, &path)?;
+    for name in names {
+        let _ = writeln!(ctx.uart, "{}", name);
+    }
+    Ok(())
+}
+
+fn cd(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let path = match args.next() {
+        Some(path) => ctx.resolve(path),
+        None => Path::root(),
+    };
+    args.finish()?;
+    if !files::stat(&mut ctx.fs.lock(), ctx.root, &path)?.is_dir {
+        return Err(FileErr::NotDirectory.into());
+    }
+    ctx.cwd = path;
+    Ok(())
+}
+
+fn pwd(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    args.finish()?;
+    let _ = writeln!(ctx.uart, "{}", ctx.cwd);
+    Ok(())
+}
+
+fn mkdir(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let path = args.path(ctx)?;
+    args.finish()?;
+    files::mkdir(&mut ctx.fs.lock(), ctx.root, &path)?;
+    Ok(())
+}
+
+fn rmdir(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let path = args.path(ctx)?;
+    args.finish()?;
+    files::rmdir(&mut ctx.fs.lock(), ctx.root, &path)?;
+    Ok(())
+}
+
+fn cat(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let path = args.path(ctx)?;
+    args.finish()?;
+    let data = files::read(&mut ctx.fs.lock(), ctx.root, &path)?;
+    ctx.uart.write_bytes(&data);
+    if data.last().map_or(false, |c| *c != b'\n') {
+        ctx.uart.write_byte(b'\n');
+    }
+    Ok(())
+}
+
+fn hexdump(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let path = args.path(ctx)?;
+    args.finish()?;
+    let data = files::read(&mut ctx.fs.lock(), ctx.root, &path)?;
+    let uart = &mut ctx.uart;
+    for (i, row) in data.chunks(16).enumerate() {
+        let _ = write!(uart, "{:08x} ", i * 16);
+        for col in 0..16 {
+            match row.get(col) {
+                Some(b) => {
+                    let _ = write!(uart, " {:02x}", b);
+                }
+                None => uart.write_bytes(b"   "),
+            }
+        }
+        uart.write_bytes(b"  |");
+        for &b in row {
+            let c = if b.is_ascii_graphic() || b == b' ' {
+                b
+            } else {
+                b'.'
+            };
+            uart.write_byte(c);
+        }
+        uart.write_bytes(b"|\n");
+    }
+    let _ = writeln!(uart, "{:08x}", data.len());
+    Ok(())
+}
+
+fn echo(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let words = args.rest();
+    let redirect = words.iter().position(|w| *w == ">" || *w == ">>");
+    let (text, target) = match redirect {
+        Some(i) => match &words[i + 1..] {
+            [path] => (&words[..i], Some((ctx.resolve(path), words[i] == ">>"))),
+            _ => return Err(ShellErr::Usage),
+        },
+        None => (words, None),
+    };
+    let mut line = text.join(" ");
+    line.push('\n');
+    match target {
+        Some((path, append)) => {
+            files::write(&mut ctx.fs.lock(), ctx.root, &path, line.as_bytes(), append)?;
+        }
+        None => ctx.uart.write_bytes(line.as_bytes()),
+    }
+    Ok(())
+}
+
+fn cp(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let from = args.path(ctx)?;
+    let to = args.path(ctx)?;
+    args.finish()?;
+    files::copy(&mut ctx.fs.lock(), ctx.root, &from, &to)?;
+    Ok(())
+}
+
+fn mv(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let from = args.path(ctx)?;
+    let to = args.path(ctx)?;
+    args.finish()?;
+    files::rename(&mut ctx.fs.lock(), ctx.root, &from, &to)?;
+    Ok(())
+}
+
+fn rm(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let paths = args.rest();
+    if paths.is_empty() {
+        return Err(ShellErr::Usage);
+    }
+    for path in paths {
+        files::remove(&mut ctx.fs.lock(), ctx.root, &ctx.resolve(path))?;
+    }
+    Ok(())
+}
+
+fn touch(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let paths = args.rest();
+    if paths.is_empty() {
+        return Err(ShellErr::Usage);
+    }
+    for path in paths {
+        files::touch(&mut ctx.fs.lock(), ctx.root, &ctx.resolve(path))?;
+    }
+    Ok(())
+}
+
+fn stat(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let path = args.path(ctx)?;
+    args.finish()?;
+    let info = files::stat(&mut ctx.fs.lock(), ctx.root, &path)?;
+    let kind = if info.is_dir { "directory" } else { "file" };
+    let _ = writeln!(ctx.uart, "{}: {}, {} bytes", path, kind, info.size);
+    Ok(())
+}
+
+fn open(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let path = args.path(ctx)?;
+    let mode = args.parse_or("mode", FileMode::RW)?;
+    args.finish()?;
+    let fd = files::open(&mut ctx.fs.lock(), ctx.root, &path, mode)?;
+    let _ = writeln!(ctx.uart, "Opened file: {:?}", fd);
+    Ok(())
+}
+
+fn fread(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let fd = args.fd()?;
+    let mut len = args.parse_or("length", 512usize)?;
+    args.finish()?;
+    let mut fs = ctx.fs.lock();
+    let mut data = [0; 512];
+    while len > 0 {
+        let n = fs.read(fd, &mut data[..len.min(512)])?;
+        if n == 0 {
+            break;
+        }
+        ctx.uart.write_bytes(&data[..n]);
+        len -= n;
+    }
+    ctx.uart.write_byte(b'\n');
+    Ok(())
+}
+
+fn fwriterand(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let fd = args.fd()?;
+    let mut len = args.parse_or("length", 512usize)?;
+    args.finish()?;
+    let mut fs = ctx.fs.lock();
+    let mut data = [0u8; 512];
+    while len > 0 {
+        let want = len.min(512);
+        ctx.entropy.read(&mut data[..want]);
+        let written = fs.write(fd, &data[..want])?;
+        if written != want {
+            return Err(ShellErr::Failed(format!(
+                "short write to {:?}, expected: {}, got: {}",
+                fd, want, written
+            )));
+        }
+        len -= want;
+    }
+    Ok(())
+}
+
+fn fseek(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let fd = args.fd()?;
+    let offset = args.parse_or("offset", 0u32)?;
+    args.finish()?;
+    ctx.fs.lock().seek(fd, fs::SeekFrom::Start(offset))?;
+    Ok(())
+}
+
+fn fclose(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let fd = args.fd()?;
+    args.finish()?;
+    ctx.fs.lock().close(fd)?;
+    Ok(())
+}
+
+fn fs_stat(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    args.finish()?;
+    let stats = ctx.fs.lock().fs_stats();
+    let _ = writeln!(ctx.uart, "FS Stats: {:?}", stats);
+    Ok(())
+}
+
+fn exec(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let argv = args.rest();
+    let name = *argv.first().ok_or(ShellErr::Usage)?;
+    let path = ctx.resolve(name);
+    let pid = elf::exec(&mut ctx.fs.lock(), ctx.root, &path.components(), argv, &[])?;
+    // The lock is already released, the process needs the file system for its syscalls.
+    match process::wait(pid)? {
+        0 => Ok(()),
+        code => Err(ShellErr::Failed(format!("{} exited with {}", name, code))),
+    }
+}
+
+fn rand(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    args.finish()?;
+    let mut data = [0u8; 16];
+    ctx.entropy.read(&mut data);
+    let _ = writeln!(ctx.uart, "Random: {:?}", data);
+    Ok(())
+}
+
+fn uptime(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    args.finish()?;
+    let up = timer::uptime();
+    let _ = writeln!(
+        ctx.uart,
+        "up {}.{:03}s ({} ticks)",
+        up.as_secs(),
+        up.subsec_millis(),
+        timer::ticks()
+    );
+    Ok(())
+}
+
+fn dmesg(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    args.finish()?;
+    ctx.uart.write_bytes(&log::dmesg());
+    Ok(())
+}
+
+fn loglevel(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    match (args.next(), args.next()) {
+        (None, _) => {
+            let _ = writeln!(ctx.uart, "{:<12} {}", "default", log::max_level());
+            for (module, filter) in log::module_levels() {
+                let _ = writeln!(ctx.uart, "{:<12} {}", module, filter);
+            }
+        }
+        (Some(level), None) => {
+            let filter = level.parse().map_err(|_| ShellErr::BadArgument("level"))?;
+            log::set_max_level(filter);
+        }
+        (Some(module), Some(level)) => {
+            args.finish()?;
+            // `default` drops the module's own level so it follows the global one again.
+            let filter = match level {
+                "default" => None,
+                level => Some(
+                    level
+                        .parse::<LevelFilter>()
+                        .map_err(|_| ShellErr::BadArgument("level"))?,
+                ),
+            };
+            log::set_module_level(module, filter);
+        }
+    }
+    Ok(())
+}
+
+fn sleep(_ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    let ms = args.parse("milliseconds")?;
+    args.finish()?;
+    sched::sleep(Duration::from_millis(ms));
+    Ok(())
+}
+
+fn mem(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    args.finish()?;
+    let frames = frames::stats();
+    let heap = heap::stats();
+    let _ = writeln!(
+        ctx.uart,
+        "Frames: {} used, {} free, {} total ({} reserved at boot), {} bytes each",
+        frames.total - frames.free,
+        frames.free,
+        frames.total,
+        frames.reserved,
+        mmu::PAGE_SIZE
+    );
+    let _ = writeln!(ctx.uart, "Heap: {} of {} bytes used", heap.used, heap.total);
+    Ok(())
+}
+
+fn ps(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    args.finish()?;
+    for thread in sched::threads() {
+        let _ = writeln!(
+            ctx.uart,
+            "{:>4} {:<12} {:?}",
+            thread.id.as_u32(),
+            thread.name,
+            thread.state
+        );
+    }
+    Ok(())
+}
+
+fn cpus(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    args.finish()?;
+    for cpu in smp::cpus() {
+        let _ = write!(
+            ctx.uart,
+            "cpu{} mpidr {:#x}: {:?}",
+            cpu.id,
+            cpu.mpidr,
+            cpu.status()
+        );
+        match psci::affinity_info(cpu.mpidr) {
+            Ok(state) => {
+                let _ = writeln!(ctx.uart, ", firmware reports {:?}", state);
+            }
+            Err(e) => {
+                let _ = writeln!(ctx.uart, ", AFFINITY_INFO failed: {:?}", e);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn debug(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    args.finish()?;
+    if !gdb::enabled() {
+        return Err(ShellErr::Failed(String::from("no port for a debugger")));
+    }
+    let _ = writeln!(ctx.uart, "Waiting for a debugger");
+    gdb::breakpoint();
+    Ok(())
+}
+
+/// Flushes the file system before the power goes, carrying on if that fails.
+fn flush(ctx: &mut Context) {
+    if let Err(e) = ctx.fs.lock().flush() {
+        let _ = writeln!(ctx.uart, "Failed to flush: {:?}", e);
+    }
+}
+
+fn poweroff(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    args.finish()?;
+    flush(ctx);
+    // Only returns if the firmware refused, in which case the shell exits and `system_off` in
+    // boot.S is the last resort.
+    let err = psci::system_off();
+    ctx.exit();
+    Err(err.into())
+}
+
+fn reboot(ctx: &mut Context, args: &mut Args) -> Result<(), ShellErr> {
+    args.finish()?;
+    flush(ctx);
+    let err = psci::system_reset();
+    ctx.exit();
+    Err(err.into())
+}
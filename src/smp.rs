@@ -0,0 +1,186 @@
+use crate::device_tree::Node;
+use crate::psci::{self, PsciErr};
+use crate::spinlock::SpinLock;
+use crate::timer::Instant;
+use crate::{frames, mmu};
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::time::Duration;
+
+/// Number of frames in each secondary core's boot stack.
+const STACK_FRAMES: usize = 4;
+/// How long to wait for a core to report in after `CPU_ON`.
+const START_TIMEOUT: Duration = Duration::from_millis(100);
+/// The affinity fields of MPIDR_EL1.
+const MPIDR_AFFINITY_MASK: u64 = 0xff_00ff_ffff;
+
+extern "C" {
+    fn secondary_start();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Offline,
+    /// `CPU_ON` succeeded but the core has not reached Rust yet.
+    Starting,
+    Online,
+    /// The core is not started through PSCI, so we cannot bring it up.
+    NoEnableMethod,
+    Failed(PsciErr),
+}
+
+/// What `secondary_start` needs before it can run Rust code. The layout must match `boot.S`.
+#[derive(Debug)]
+#[repr(C)]
+struct BootArgs {
+    stack_top: u64,
+    regs: mmu::CpuState,
+}
+
+/// Data owned by a single core. The running core's is found through TPIDR_EL1.
+#[repr(C)]
+pub struct PerCpu {
+    /// Must come first, `secondary_start` reads it through the pointer it is given.
+    boot: BootArgs,
+    /// Index of this core in `cpus()`.
+    pub id: usize,
+    pub mpidr: u64,
+    status: SpinLock<Status>,
+}
+
+impl PerCpu {
+    pub fn status(&self) -> Status {
+        *self.status.lock()
+    }
+}
+
+static mut CPUS: &[PerCpu] = &[];
+
+/// Every core listed under `/cpus`, in device tree order.
+pub fn cpus() -> &'static [PerCpu] {
+    unsafe { CPUS }
+}
+
+/// The running core's data, once `init` has run.
+pub fn current() -> Option<&'static PerCpu> {
+    let ptr: u64;
+    unsafe { asm!("mrs {}, tpidr_el1", out(reg) ptr) };
+    unsafe { (ptr as *const PerCpu).as_ref() }
+}
+
+pub fn cpu_id() -> usize {
+    current().map_or(0, |cpu| cpu.id)
+}
+
+fn mpidr() -> u64 {
+    let mpidr: u64;
+    unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr) };
+    mpidr & MPIDR_AFFINITY_MASK
+}
+
+/// Cleans and invalidates `[start, end)` to the point of coherency, so that a core with its
+/// caches still off sees what we wrote.
+fn clean_dcache(start: usize, end: usize) {
+    let ctr: u64;
+    unsafe { asm!("mrs {}, ctr_el0", out(reg) ctr) };
+    let line = 4 << ((ctr >> 16) & 0xf);
+    for addr in (start & !(line - 1)..end).step_by(line) {
+        unsafe { asm!("dc civac, {}", in(reg) addr) };
+    }
+    unsafe { asm!("dsb sy") };
+}
+
+/// Reads `/cpus`, sets up per-CPU data for every core and starts the secondaries with PSCI
+/// `CPU_ON`. Returns how many cores are online.
+///
+/// Secondaries park once they are up since nothing else is multicore-safe yet.
+pub fn init(root: &Node) -> usize {
+    let cpus_node = if let Some(cpus) = root.child_by_name("cpus") {
+        cpus
+    } else {
+        return 1;
+    };
+    let address_cells = cpus_node.address_cells();
+    let boot_mpidr = mpidr();
+
+    let mut found = Vec::new();
+    let cores = cpus_node.children().filter(|child| {
+        child
+            .prop_by_name("device_type")
+            .map_or(false, |p| p.contains_str("cpu"))
+    });
+    for core in cores {
+        let mpidr = if let Some(reg) = core.prop_by_name("reg") {
+            crate::device_tree::regs_to_usize(reg.value, address_cells).0 as u64
+        } else {
+            continue;
+        };
+        let status = if mpidr == boot_mpidr {
+            Status::Online
+        } else if core
+            .prop_by_name("enable-method")
+            .map_or(false, |p| p.contains_str("psci"))
+        {
+            Status::Offline
+        } else {
+            Status::NoEnableMethod
+        };
+        found.push(PerCpu {
+            boot: BootArgs {
+                stack_top: 0,
+                regs: mmu::cpu_state(),
+            },
+            id: found.len(),
+            mpidr,
+            status: SpinLock::new(status),
+        });
+    }
+    if found.is_empty() {
+        return 1;
+    }
+    let all: &'static mut [PerCpu] = found.leak();
+    if let Some(boot) = all.iter().find(|cpu| cpu.mpidr == boot_mpidr) {
+        unsafe { asm!("msr tpidr_el1, {}", in(reg) boot as *const PerCpu) };
+    }
+
+    for cpu in all.iter_mut() {
+        if cpu.status() != Status::Offline {
+            continue;
+        }
+        let stack = if let Some(stack) = frames::alloc_contiguous(STACK_FRAMES) {
+            stack
+        } else {
+            *cpu.status.lock() = Status::Failed(PsciErr::InternalFailure);
+            continue;
+        };
+        cpu.boot.stack_top = (stack + STACK_FRAMES * mmu::PAGE_SIZE) as u64;
+        let start = cpu as *const PerCpu as usize;
+        clean_dcache(start, start + core::mem::size_of::<PerCpu>());
+
+        *cpu.status.lock() = Status::Starting;
+        if let Err(e) = psci::cpu_on(cpu.mpidr, secondary_start as usize, start as u64) {
+            *cpu.status.lock() = Status::Failed(e);
+            frames::free(stack, STACK_FRAMES);
+            continue;
+        }
+        let deadline = Instant::now() + START_TIMEOUT;
+        while cpu.status() == Status::Starting && Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+    }
+    unsafe { CPUS = all };
+    cpus()
+        .iter()
+        .filter(|c| c.status() == Status::Online)
+        .count()
+}
+
+/// Where a secondary core lands from `secondary_start`, with the MMU on and its own stack.
+#[no_mangle]
+extern "C" fn secondary_main(cpu: &'static PerCpu) -> ! {
+    unsafe { asm!("msr tpidr_el1, {}", in(reg) cpu as *const PerCpu) };
+    *cpu.status.lock() = Status::Online;
+    loop {
+        unsafe { asm!("wfe") };
+    }
+}
@@ -0,0 +1,325 @@
+use crate::exceptions::{self, TrapFrame};
+use crate::fs::{self, FileMode, SeekFrom};
+use crate::path::Path;
+use crate::process::{self, FdEntry, KernelFs};
+use core::convert::TryFrom;
+
+/// Calls made with `svc #0`. The number goes in x8 and up to six arguments in x0-x5. The
+/// result comes back in x0, with errors returned as the negated `SyscallErr`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Syscall {
+    /// exit(code) -> !
+    Exit = 0,
+    /// open(path, path_len, mode) -> fd
+    Open = 1,
+    /// read(fd, buf, len) -> bytes read
+    Read = 2,
+    /// write(fd, buf, len) -> bytes written
+    Write = 3,
+    /// seek(fd, whence, offset) -> 0, where whence is 0 for the start, 1 for the current
+    /// position and 2 for the end.
+    Seek = 4,
+    /// close(fd) -> 0
+    Close = 5,
+    /// mkdir(path, path_len) -> 0
+    Mkdir = 6,
+    /// rmdir(path, path_len) -> 0
+    Rmdir = 7,
+    /// unlink(path, path_len) -> 0
+    Unlink = 8,
+    /// stat(fd, *mut Stat) -> 0
+    Stat = 9,
+}
+
+impl Syscall {
+    fn from_u64(v: u64) -> Option<Self> {
+        use Syscall::*;
+        let call = match v {
+            0 => Exit,
+            1 => Open,
+            2 => Read,
+            3 => Write,
+            4 => Seek,
+            5 => Close,
+            6 => Mkdir,
+            7 => Rmdir,
+            8 => Unlink,
+            9 => Stat,
+            _ => return None,
+        };
+        Some(call)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallErr {
+    NoSys = 1,
+    BadFd = 2,
+    /// A pointer argument was not readable or writable by the process.
+    Fault = 3,
+    Inval = 4,
+    /// The process or the file system ran out of descriptors.
+    NoFds = 5,
+    NotFound = 6,
+    Exists = 7,
+    NotDir = 8,
+    NotEmpty = 9,
+    Io = 10,
+    /// Directories can't be read or written like files.
+    IsDir = 11,
+}
+
+/// What `stat` writes back to user memory.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Stat {
+    pub size: u64,
+    pub is_dir: u64,
+}
+
+impl From<fs::OpenErr> for SyscallErr {
+    fn from(e: fs::OpenErr) -> Self {
+        use fs::OpenErr::*;
+        match e {
+            PathDoesNotExist => SyscallErr::NotFound,
+            NewButFileExists => SyscallErr::Exists,
+            MustProvideFileName | ModeCannotCreateFile => SyscallErr::Inval,
+            NotInsideDirectory => SyscallErr::NotDir,
+            NoFreeFDs | FileOpenTooMuch => SyscallErr::NoFds,
+            _ => SyscallErr::Io,
+        }
+    }
+}
+
+impl From<fs::RmdirErr> for SyscallErr {
+    fn from(e: fs::RmdirErr) -> Self {
+        match e {
+            fs::RmdirErr::NotDirectory => SyscallErr::NotDir,
+            fs::RmdirErr::NotEmpty => SyscallErr::NotEmpty,
+            fs::RmdirErr::OpenErr(e) => e.into(),
+            _ => SyscallErr::Io,
+        }
+    }
+}
+
+type SyscallResult = Result<u64, SyscallErr>;
+
+/// Handles an `svc #0` from EL0, writing the result into the caller's x0.
+pub fn dispatch(frame: &mut TrapFrame) {
+    let mut args = [0; 6];
+    args.copy_from_slice(&frame.regs[..6]);
+    // Syscalls can take a while, so let other threads run in the meantime.
+    exceptions::enable_interrupts();
+    let result = match Syscall::from_u64(frame.regs[8]) {
+        Some(Syscall::Exit) => process::exit_current(args[0] as i64 as isize),
+        Some(call) => handle(call, args),
+        None => Err(SyscallErr::NoSys),
+    };
+    exceptions::disable_interrupts();
+    frame.regs[0] = match result {
+        Ok(v) => v,
+        Err(e) => (-(e as i64)) as u64,
+    };
+}
+
+fn handle(call: Syscall, args: [u64; 6]) -> SyscallResult {
+    let fs = process::fs().ok_or(SyscallErr::Io)?;
+    match call {
+        Syscall::Exit => unreachable!(),
+        Syscall::Open => {
+            let path = user_str(args[0], args[1])?;
+            let mode = file_mode(args[2]).ok_or(SyscallErr::Inval)?;
+            let fd = with_root(&mut fs.lock(), |fs, root| {
+                Ok(fs.open(root, &resolve(path).components(), mode)?)
+            })?;
+            match process::with_current(|p| p.files.insert(FdEntry::File(fd))).flatten() {
+                Some(user_fd) => Ok(user_fd as u64),
+                None => {
+                    let _ = fs.lock().close(fd);
+                    Err(SyscallErr::NoFds)
+                }
+            }
+        }
+        Syscall::Read => {
+            let buf = unsafe { user_buf(args[1], args[2], true)? };
+            match fd_entry(args[0])? {
+                FdEntry::Console => {
+                    let mut console = process::console().ok_or(SyscallErr::Io)?;
+                    Ok(console.read_line(buf, true).len() as u64)
+                }
+                FdEntry::File(fd) => {
+                    let mut fs = fs.lock();
+                    if fs.is_directory(fd).map_err(|_| SyscallErr::Io)? {
+                        return Err(SyscallErr::IsDir);
+                    }
+                    fs.read(fd, buf).map(|n| n as u64).map_err(|e| match e {
+                        fs::ReadErr::NotReadable => SyscallErr::BadFd,
+                        _ => SyscallErr::Io,
+                    })
+                }
+            }
+        }
+        Syscall::Write => {
+            let buf = unsafe { user_buf(args[1], args[2], false)? };
+            match fd_entry(args[0])? {
+                FdEntry::Console => {
+                    let mut console = process::console().ok_or(SyscallErr::Io)?;
+                    console.write_bytes(buf);
+                    Ok(buf.len() as u64)
+                }
+                FdEntry::File(fd) => {
+                    let mut fs = fs.lock();
+                    if fs.is_directory(fd).map_err(|_| SyscallErr::Io)? {
+                        return Err(SyscallErr::IsDir);
+                    }
+                    fs.write(fd, buf).map(|n| n as u64).map_err(|e| match e {
+                        fs::WriteErr::ReadOnly => SyscallErr::BadFd,
+                        _ => SyscallErr::Io,
+                    })
+                }
+            }
+        }
+        Syscall::Seek => {
+            let fd = file_fd(args[0])?;
+            let offset = args[2] as i64;
+            let from = match args[1] {
+                0 => SeekFrom::Start(u32::try_from(offset).map_err(|_| SyscallErr::Inval)?),
+                1 => SeekFrom::Current(i32::try_from(offset).map_err(|_| SyscallErr::Inval)?),
+                2 => SeekFrom::End(i32::try_from(offset).map_err(|_| SyscallErr::Inval)?),
+                _ => return Err(SyscallErr::Inval),
+            };
+            match fs.lock().seek(fd, from) {
+                Ok(()) => Ok(0),
+                Err(fs::SeekErr::BeforeStart) => Err(SyscallErr::Inval),
+                Err(_) => Err(SyscallErr::Io),
+            }
+        }
+        Syscall::Close => {
+            let entry = process::with_current(|p| p.files.remove(args[0] as usize))
+                .flatten()
+                .ok_or(SyscallErr::BadFd)?;
+            if let FdEntry::File(fd) = entry {
+                fs.lock().close(fd).map_err(|_| SyscallErr::Io)?;
+            }
+            Ok(0)
+        }
+        Syscall::Mkdir => {
+            let path = user_str(args[0], args[1])?;
+            let path = resolve(path);
+            let components = path.components();
+            let (name, parent) = components.split_last().ok_or(SyscallErr::Inval)?;
+            with_root(&mut fs.lock(), |fs, root| {
+                let dir = if parent.is_empty() {
+                    root
+                } else {
+                    fs.open(root, parent, FileMode::MustExist)?
+                };
+                let made = fs.mkdir(dir, name);
+                if dir != root {
+                    let _ = fs.close(dir);
+                }
+                match made {
+                    Ok(new_dir) => {
+                        let _ = fs.close(new_dir);
+                        Ok(0)
+                    }
+                    Err(fs::MkdirErr::OpenErr(e)) => Err(e.into()),
+                    Err(_) => Err(SyscallErr::Io),
+                }
+            })
+        }
+        Syscall::Rmdir => {
+            let path = user_str(args[0], args[1])?;
+            with_root(&mut fs.lock(), |fs, root| {
+                fs.rmdir(root, &resolve(path).components())?;
+                Ok(0)
+            })
+        }
+        Syscall::Unlink => {
+            let path = user_str(args[0], args[1])?;
+            with_root(&mut fs.lock(), |fs, root| {
+                match fs.unlink(root, &resolve(path).components()) {
+                    Ok(()) => Ok(0),
+                    Err(fs::UnlinkErr::OpenErr(e)) => Err(e.into()),
+                    Err(_) => Err(SyscallErr::Io),
+                }
+            })
+        }
+        Syscall::Stat => {
+            let fd = file_fd(args[0])?;
+            let out = unsafe { user_buf(args[1], core::mem::size_of::<Stat>() as u64, true)? };
+            let stat = {
+                let mut fs = fs.lock();
+                Stat {
+                    size: fs.stat(fd).map_err(|_| SyscallErr::Io)?.size as u64,
+                    is_dir: fs.is_directory(fd).map_err(|_| SyscallErr::Io)? as u64,
+                }
+            };
+            unsafe { (out.as_mut_ptr() as *mut Stat).write_unaligned(stat) };
+            Ok(0)
+        }
+    }
+}
+
+/// Runs `f` with a descriptor for the root directory, which every path is resolved from.
+fn with_root<T>(
+    fs: &mut KernelFs,
+    f: impl FnOnce(&mut KernelFs, fs::FileDescriptor) -> Result<T, SyscallErr>,
+) -> Result<T, SyscallErr> {
+    let root = fs.root_dir(FileMode::R).map_err(|()| SyscallErr::NoFds)?;
+    let out = f(fs, root);
+    let _ = fs.close(root);
+    out
+}
+
+/// Processes have no working directory, so every path is taken relative to the root.
+fn resolve(path: &str) -> Path {
+    Path::root().join(&Path::parse(path))
+}
+
+fn file_mode(v: u64) -> Option<FileMode> {
+    let mode = match v {
+        1 => FileMode::R,
+        2 => FileMode::W,
+        3 => FileMode::RW,
+        4 => FileMode::New,
+        8 => FileMode::MustExist,
+        _ => return None,
+    };
+    Some(mode)
+}
+
+fn fd_entry(fd: u64) -> Result<FdEntry, SyscallErr> {
+    process::with_current(|p| p.files.get(fd as usize))
+        .flatten()
+        .ok_or(SyscallErr::BadFd)
+}
+
+fn file_fd(fd: u64) -> Result<fs::FileDescriptor, SyscallErr> {
+    match fd_entry(fd)? {
+        FdEntry::File(fd) => Ok(fd),
+        FdEntry::Console => Err(SyscallErr::Inval),
+    }
+}
+
+/// Borrows `[ptr, ptr + len)` of the calling process's memory after checking that it may
+/// access it. The process's address space is the active one, so the pointer is used as is.
+unsafe fn user_buf(ptr: u64, len: u64, write: bool) -> Result<&'static mut [u8], SyscallErr> {
+    let (ptr, len) = (ptr as usize, len as usize);
+    let ok = process::with_current(|p| {
+        p.space()
+            .map(|space| space.user_accessible(ptr, len, write))
+            .unwrap_or(false)
+    });
+    if ok != Some(true) {
+        return Err(SyscallErr::Fault);
+    }
+    Ok(core::slice::from_raw_parts_mut(ptr as *mut u8, len))
+}
+
+fn user_str(ptr: u64, len: u64) -> Result<&'static str, SyscallErr> {
+    let bytes = unsafe { user_buf(ptr, len, false)? };
+    core::str::from_utf8(bytes).map_err(|_| SyscallErr::Inval)
+}
@@ -0,0 +1,156 @@
+use crate::device_tree::Node;
+use crate::{exceptions, gic};
+use core::arch::asm;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+/// Maximum number of functions which can be run on every tick.
+const MAX_TICK_CALLBACKS: usize = 8;
+
+/// The counter value when the timer was initialized.
+static BOOT_COUNT: AtomicU64 = AtomicU64::new(0);
+/// Number of counter ticks between timer interrupts.
+static TICK_PERIOD: AtomicU64 = AtomicU64::new(0);
+/// Number of timer interrupts taken since init.
+static TICKS: AtomicU64 = AtomicU64::new(0);
+/// Set once the periodic tick has been started.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+static mut TICK_CALLBACKS: [Option<fn(Instant)>; MAX_TICK_CALLBACKS] = [None; MAX_TICK_CALLBACKS];
+
+/// Frequency of the system counter in Hz.
+#[inline]
+pub fn frequency() -> u64 {
+    let freq: u64;
+    unsafe { asm!("mrs {}, cntfrq_el0", out(reg) freq) };
+    freq
+}
+
+#[inline]
+fn counter() -> u64 {
+    let count: u64;
+    unsafe { asm!("isb", "mrs {}, cntpct_el0", out(reg) count) };
+    count
+}
+
+fn duration_to_count(d: Duration) -> u64 {
+    (d.as_nanos() * frequency() as u128 / 1_000_000_000) as u64
+}
+
+fn count_to_duration(count: u64) -> Duration {
+    Duration::from_nanos((count as u128 * 1_000_000_000 / frequency() as u128) as u64)
+}
+
+/// A point on the monotonic system counter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Self {
+        Instant(counter())
+    }
+
+    /// The time elapsed from `earlier` until `self`, or zero if `earlier` is later.
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        count_to_duration(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+    fn add(self, d: Duration) -> Instant {
+        Instant(self.0 + duration_to_count(d))
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Time since the timer was initialized.
+pub fn uptime() -> Duration {
+    Instant::now().duration_since(Instant(BOOT_COUNT.load(Ordering::Relaxed)))
+}
+
+/// Whether the periodic tick has been started.
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::Relaxed)
+}
+
+/// Number of periodic ticks since the timer was initialized.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Arms the EL1 physical timer to fire in `count` ticks.
+fn arm(count: u64) {
+    unsafe {
+        asm!("msr cntp_tval_el0, {}", in(reg) count);
+        // ENABLE, with IMASK clear
+        asm!("msr cntp_ctl_el0, {}", "isb", in(reg) 1u64);
+    }
+}
+
+fn on_timer(_irq: u32, _data: usize) {
+    arm(TICK_PERIOD.load(Ordering::Relaxed));
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    let now = Instant::now();
+    for callback in unsafe { TICK_CALLBACKS.iter() }.flatten() {
+        callback(now);
+    }
+}
+
+/// Finds the architected timer in the device tree, hooks its non-secure physical interrupt up
+/// to the GIC, and starts ticking every `tick`.
+pub fn init(root: &Node, tick: Duration) -> Result<(), ()> {
+    let node = root
+        .children()
+        .find(|child| child.is_compatible("arm,armv8-timer"))
+        .ok_or(())?;
+    // Interrupts are listed as secure, non-secure physical, virtual, hypervisor.
+    let irq = gic::irqs_of(&node).nth(1).ok_or(())?;
+    BOOT_COUNT.store(counter(), Ordering::Relaxed);
+    TICK_PERIOD.store(duration_to_count(tick).max(1), Ordering::Relaxed);
+    gic::register_handler(irq, on_timer, 0)?;
+    arm(TICK_PERIOD.load(Ordering::Relaxed));
+    RUNNING.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Registers a function to be called from the timer interrupt on every tick.
+pub fn register_tick_callback(f: fn(Instant)) -> Result<(), ()> {
+    exceptions::without_interrupts(|| {
+        let slot = unsafe { TICK_CALLBACKS.iter_mut() }
+            .find(|slot| slot.is_none())
+            .ok_or(())?;
+        *slot = Some(f);
+        Ok(())
+    })
+}
+
+/// Blocks for at least `d`. Once the scheduler is running other threads run in the meantime,
+/// before that the cpu sleeps between ticks. Without ticks to wake it, because the timer isn't
+/// running or interrupts are masked, it spins on the counter instead.
+pub fn sleep(d: Duration) {
+    let deadline = Instant::now() + d;
+    if !is_running() || !exceptions::interrupts_enabled() {
+        while Instant::now() < deadline {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+    if crate::sched::is_running() {
+        return crate::sched::sleep_until(deadline);
+    }
+    while Instant::now() < deadline {
+        gic::wait_for_interrupt();
+    }
+}
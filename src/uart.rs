@@ -1,5 +1,174 @@
+use crate::device_tree::Node;
+use crate::exceptions;
+use crate::gic::{self, IrqSpec};
+use crate::sched::{self, ThreadId};
+use crate::spinlock::SpinLock;
 use core::{fmt::Write, ptr, str};
 
+// PL011 register offsets
+const DR: usize = 0x00;
+const FR: usize = 0x18;
+const IBRD: usize = 0x24;
+const FBRD: usize = 0x28;
+const LCR_H: usize = 0x2c;
+const CR: usize = 0x30;
+const IFLS: usize = 0x34;
+const IMSC: usize = 0x38;
+const MIS: usize = 0x40;
+const ICR: usize = 0x44;
+
+const FR_BUSY: u32 = 1 << 3;
+const FR_RXFE: u32 = 1 << 4;
+const FR_TXFF: u32 = 1 << 5;
+
+const LCR_H_PEN: u32 = 1 << 1;
+const LCR_H_EPS: u32 = 1 << 2;
+const LCR_H_STP2: u32 = 1 << 3;
+const LCR_H_FEN: u32 = 1 << 4;
+const LCR_H_WLEN_SHIFT: u32 = 5;
+
+const CR_UARTEN: u32 = 1 << 0;
+const CR_TXE: u32 = 1 << 8;
+const CR_RXE: u32 = 1 << 9;
+
+const INT_RX: u32 = 1 << 4;
+const INT_TX: u32 = 1 << 5;
+/// Receive timeout, raised when bytes sit in the RX FIFO below the trigger level.
+const INT_RT: u32 = 1 << 6;
+
+/// Interrupt when the RX FIFO is half full or the TX FIFO is down to an eighth.
+const IFLS_RX_HALF_TX_EIGHTH: u32 = 0b010 << 3;
+
+const RX_BUFFER: usize = 256;
+const TX_BUFFER: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+/// Baud rate and framing for a PL011.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineConfig {
+    /// Frequency of UARTCLK in Hz.
+    pub clock: u32,
+    pub baud: u32,
+    /// 5 to 8.
+    pub data_bits: u8,
+    pub parity: Parity,
+    /// 1 or 2.
+    pub stop_bits: u8,
+}
+
+impl LineConfig {
+    pub const DEFAULT_BAUD: u32 = 115200;
+
+    /// Reads the settings for `node`. The clock comes from its `clock-frequency`, or from the
+    /// first clock it references in `clocks`. The baud rate comes from `options`, which is the
+    /// `<baud><parity><bits>` suffix of `stdout-path` such as `115200n8`, then from
+    /// `current-speed`.
+    pub fn from_node(root: &Node, node: &Node, options: &[u8]) -> Option<Self> {
+        let clock = node
+            .prop_by_name("clock-frequency")
+            .and_then(|p| p.as_u32())
+            .or_else(|| {
+                let phandle = node.prop_by_name("clocks")?.as_u32()?;
+                root.node_by_phandle(phandle)?
+                    .prop_by_name("clock-frequency")?
+                    .as_u32()
+            })?;
+        let mut config = LineConfig {
+            clock,
+            baud: node
+                .prop_by_name("current-speed")
+                .and_then(|p| p.as_u32())
+                .unwrap_or(Self::DEFAULT_BAUD),
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: 1,
+        };
+        let digits = options.iter().take_while(|c| c.is_ascii_digit()).count();
+        if let Some(baud) = str::from_utf8(&options[..digits])
+            .ok()
+            .and_then(|b| b.parse().ok())
+        {
+            config.baud = baud;
+        }
+        let mut rest = options[digits..].iter();
+        match rest.next() {
+            Some(b'e') => config.parity = Parity::Even,
+            Some(b'o') => config.parity = Parity::Odd,
+            _ => {}
+        }
+        if let Some(bits @ b'5'..=b'8') = rest.next() {
+            config.data_bits = bits - b'0';
+        }
+        Some(config)
+    }
+}
+
+/// A fixed size byte queue.
+struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    /// Index of the oldest byte.
+    head: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    const fn new() -> Self {
+        RingBuffer {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Appends `v`, returning false if there was no room for it.
+    fn push(&mut self, v: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = v;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let v = self.buf[self.head];
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        Some(v)
+    }
+}
+
+/// State of the UART driven by interrupts, shared between its handler and every `UART` handle
+/// with the same base.
+struct Buffered {
+    base: usize,
+    rx: RingBuffer<RX_BUFFER>,
+    tx: RingBuffer<TX_BUFFER>,
+    /// Bytes which arrived while `rx` was full.
+    dropped: usize,
+    /// Thread sleeping in `read_byte`.
+    reader: Option<ThreadId>,
+}
+
+static BUFFERED: SpinLock<Option<Buffered>> = SpinLock::new(None);
+
 pub struct UART(*mut u32);
 
 impl UART {
@@ -7,11 +176,135 @@ impl UART {
         UART(base_addr)
     }
 
+    pub fn base(&self) -> *mut u32 {
+        self.0
+    }
+
+    fn read_reg(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.0.add(offset / 4)) }
+    }
+
+    fn write_reg(&mut self, offset: usize, v: u32) {
+        unsafe { ptr::write_volatile(self.0.add(offset / 4), v) }
+    }
+
+    /// Programs the baud rate and framing and enables the FIFOs.
+    pub fn configure(&mut self, config: &LineConfig) {
+        while self.read_reg(FR) & FR_BUSY != 0 {}
+        self.write_reg(CR, 0);
+        // Clearing FEN flushes the transmit FIFO.
+        self.write_reg(LCR_H, 0);
+
+        // The divisor is UARTCLK / (16 * baud) in 16.6 fixed point.
+        let divisor = (4 * config.clock as u64 + config.baud as u64 / 2) / config.baud as u64;
+        self.write_reg(IBRD, (divisor >> 6) as u32);
+        self.write_reg(FBRD, (divisor & 0x3f) as u32);
+
+        let mut lcr = LCR_H_FEN | ((config.data_bits.clamp(5, 8) as u32 - 5) << LCR_H_WLEN_SHIFT);
+        match config.parity {
+            Parity::None => {}
+            Parity::Even => lcr |= LCR_H_PEN | LCR_H_EPS,
+            Parity::Odd => lcr |= LCR_H_PEN,
+        }
+        if config.stop_bits == 2 {
+            lcr |= LCR_H_STP2;
+        }
+        // LCR_H must be written after the baud rate registers for them to take effect.
+        self.write_reg(LCR_H, lcr);
+        self.write_reg(CR, CR_UARTEN | CR_TXE | CR_RXE);
+    }
+
+    /// Switches this UART over to interrupt driven, buffered I/O.
+    pub fn enable_interrupts(&mut self, spec: IrqSpec) -> Result<(), ()> {
+        let base = self.0 as usize;
+        *BUFFERED.lock() = Some(Buffered {
+            base,
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            dropped: 0,
+            reader: None,
+        });
+        self.write_reg(IFLS, IFLS_RX_HALF_TX_EIGHTH);
+        self.write_reg(ICR, 0x7ff);
+        if let Err(()) = gic::register_handler(spec, on_interrupt, base) {
+            *BUFFERED.lock() = None;
+            return Err(());
+        }
+        self.write_reg(IMSC, INT_RX | INT_RT | INT_TX);
+        Ok(())
+    }
+
+    /// Whether this handle refers to the interrupt driven UART.
+    fn is_buffered(&self, state: &Option<Buffered>) -> bool {
+        matches!(state, Some(b) if b.base == self.0 as usize)
+    }
+
+    /// Moves bytes from the TX ring into the FIFO until one of them runs out.
+    fn fill_tx_fifo(&mut self, tx: &mut RingBuffer<TX_BUFFER>) {
+        while self.read_reg(FR) & FR_TXFF == 0 {
+            match tx.pop() {
+                Some(v) => self.write_reg(DR, v as u32),
+                None => break,
+            }
+        }
+    }
+
+    /// Drains the RX FIFO into the RX ring.
+    fn drain_rx_fifo(&mut self, state: &mut Buffered) {
+        while self.read_reg(FR) & FR_RXFE == 0 {
+            let v = self.read_reg(DR) as u8;
+            if !state.rx.push(v) {
+                state.dropped += 1;
+            }
+        }
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
-        unsafe {
-            ptr::write_volatile(self.0, byte as u32);
-            while ptr::read_volatile(self.0.offset(0x18 / 4)) & 1 << 3 != 0 {}
+        let irqs_enabled = exceptions::interrupts_enabled();
+        loop {
+            {
+                let mut state = BUFFERED.lock();
+                let buffered = match state.as_mut() {
+                    Some(b) if b.base == self.0 as usize => b,
+                    _ => break,
+                };
+                self.fill_tx_fifo(&mut buffered.tx);
+                if buffered.tx.is_empty() && self.read_reg(FR) & FR_TXFF == 0 {
+                    self.write_reg(DR, byte as u32);
+                    return;
+                }
+                // Without interrupts nothing would drain the ring, which happens when panicking
+                // or inside of an exception handler, so keep feeding the FIFO by hand instead.
+                if irqs_enabled && buffered.tx.push(byte) {
+                    return;
+                }
+            }
+            if irqs_enabled {
+                // The ring is full, wait for the TX interrupt to make room.
+                gic::wait_for_interrupt();
+            }
+        }
+        self.poll_write_byte(byte);
+    }
+
+    /// Writes a byte straight into the FIFO, for ports that are never interrupt driven like the
+    /// debugger's.
+    pub fn poll_write_byte(&mut self, byte: u8) {
+        while self.read_reg(FR) & FR_TXFF != 0 {}
+        self.write_reg(DR, byte as u32);
+    }
+
+    /// Turns this handle into one which only ever writes with `poll_write_byte`.
+    pub fn polled(self) -> PolledWriter {
+        PolledWriter(self)
+    }
+
+    /// Waits for a byte straight from the FIFO, for ports that are never interrupt driven.
+    pub fn poll_read_byte(&mut self) -> u8 {
+        while self.read_reg(FR) & FR_RXFE != 0 {
+            core::hint::spin_loop();
         }
+        self.read_reg(DR) as u8
     }
 
     pub fn write_bytes(&mut self, s: &[u8]) {
@@ -20,12 +313,50 @@ impl UART {
         }
     }
 
-    #[inline(never)]
+    /// Returns the next received byte, if one is waiting.
+    pub fn try_read_byte(&mut self) -> Option<u8> {
+        let mut state = BUFFERED.lock();
+        if self.is_buffered(&state) {
+            let buffered = state.as_mut().unwrap();
+            // Pick up anything still under the FIFO trigger level as well.
+            self.drain_rx_fifo(buffered);
+            return buffered.rx.pop();
+        }
+        drop(state);
+        if self.read_reg(FR) & FR_RXFE != 0 {
+            return None;
+        }
+        Some(self.read_reg(DR) as u8)
+    }
+
+    /// Waits for the next received byte. Other threads run in the meantime when this UART is
+    /// interrupt driven.
     pub fn read_byte(&mut self) -> u8 {
-        unsafe {
-            ptr::write_volatile(self.0.offset(0x38 / 4), 1 << 4);
-            while ptr::read_volatile(self.0.offset(0x18 / 4)) & (1 << 4) != 0 {}
-            ptr::read_volatile(self.0) as u8
+        loop {
+            if let Some(v) = self.try_read_byte() {
+                return v;
+            }
+            let parked = exceptions::without_interrupts(|| {
+                let current = sched::current();
+                {
+                    let mut state = BUFFERED.lock();
+                    if !self.is_buffered(&state) || current.is_none() {
+                        return false;
+                    }
+                    let buffered = state.as_mut().unwrap();
+                    if !buffered.rx.is_empty() {
+                        return true;
+                    }
+                    buffered.reader = current;
+                }
+                // Interrupts stay masked until we are off the cpu, so the wakeup cannot be
+                // missed.
+                unsafe { sched::block_current() };
+                true
+            });
+            if !parked {
+                core::hint::spin_loop();
+            }
         }
     }
 
@@ -75,3 +406,41 @@ impl Write for UART {
         Ok(())
     }
 }
+
+/// Writes straight into the FIFO of a UART without ever taking the lock on its buffered state,
+/// for panics and fatal exceptions which may have interrupted whoever held it.
+pub struct PolledWriter(UART);
+
+impl Write for PolledWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.0.poll_write_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+/// Services the RX and TX interrupts of the buffered UART at `base`.
+fn on_interrupt(_irq: u32, base: usize) {
+    let mut uart = unsafe { UART::new(base as _) };
+    let mut state = BUFFERED.lock();
+    let buffered = match state.as_mut() {
+        Some(b) if b.base == base => b,
+        _ => return,
+    };
+    let pending = uart.read_reg(MIS);
+    if pending & (INT_RX | INT_RT) != 0 {
+        uart.drain_rx_fifo(buffered);
+        if let Some(reader) = buffered.reader.take() {
+            sched::wake(reader);
+        }
+    }
+    if pending & INT_TX != 0 {
+        uart.fill_tx_fifo(&mut buffered.tx);
+        if buffered.tx.is_empty() {
+            // Nothing left to send, so quiet the interrupt until the next write refills the FIFO.
+            uart.write_reg(ICR, INT_TX);
+        }
+    }
+    uart.write_reg(ICR, pending & (INT_RX | INT_RT));
+}
@@ -1,4 +1,12 @@
+use crate::exceptions;
+use crate::gic::{self, IrqSpec};
+use crate::sched::{self, ThreadId};
+use crate::spinlock::SpinLock;
 use crate::utils::*;
+pub use crate::virtqueue::{QueueMemory, VirtQAvailable, VirtQDesc, VirtQUsed, Virtqueue};
+use crate::virtqueue::{Token, MAX_QUEUE_SIZE};
+use crate::{error, info};
+use alloc::vec::Vec;
 use core::ptr::{read_volatile, write_volatile};
 
 #[derive(Debug)]
@@ -83,93 +91,122 @@ pub struct VirtIORegs {
 
 const MAGIC: u32 = 0x74726976;
 
-#[derive(Copy, Clone, Debug)]
-#[repr(C, align(16))]
-pub struct VirtQDesc {
-    addr: LEU64,
-    len: LEU32,
-    flags: Endian<u16, Little>,
-    next: Endian<u16, Little>,
+// Bits of `interrupt_status` and `interrupt_ack`
+/// The device has used buffers in at least one of its queues.
+const INT_USED_RING: u32 = 1;
+/// The device's configuration space has changed.
+const INT_CONFIG_CHANGE: u32 = 2;
+
+/// Called from the interrupt handler when a device's configuration changes.
+pub type ConfigHandler = fn(regs: &mut VirtIORegs);
+
+/// Interrupt state of a device, shared between its handler and whoever waits on its queues.
+struct Interrupts {
+    base: usize,
+    irq: u32,
+    /// Threads sleeping until the device uses buffers.
+    waiters: Vec<ThreadId>,
+    on_config_change: Option<ConfigHandler>,
 }
 
-impl VirtQDesc {
-    pub const fn empty() -> VirtQDesc {
-        VirtQDesc {
-            addr: Endian::from_raw(0),
-            len: Endian::from_raw(0),
-            flags: Endian::from_raw(0),
-            next: Endian::from_raw(0),
+static INTERRUPTS: SpinLock<Vec<Interrupts>> = SpinLock::new(Vec::new());
+
+impl VirtIORegs {
+    pub unsafe fn new<'a>(base: *mut VirtIORegs) -> Option<&'a mut VirtIORegs> {
+        let candidate = &mut *base;
+        if candidate.magic.native() == MAGIC
+            && candidate.version.native() == 2
+            && candidate.device_id.native() != DeviceId::Invalid as u32
+        {
+            Some(candidate)
+        } else {
+            None
         }
     }
-}
 
-#[derive(Copy, Clone, Debug)]
-#[repr(C, align(2))]
-pub struct VirtQAvailable {
-    flags: Endian<u16, Little>,
-    idx: Endian<u16, Little>,
-    ring: [Endian<u16, Little>; 128],
-    used_event: Endian<u16, Little>,
-}
+    fn base(&self) -> usize {
+        self as *const Self as usize
+    }
 
-impl VirtQAvailable {
-    pub const fn empty() -> VirtQAvailable {
-        VirtQAvailable {
-            flags: Endian::from_raw(0),
-            idx: Endian::from_raw(0),
-            ring: [Endian::from_raw(0); 128],
-            used_event: Endian::from_raw(0),
+    /// Has the device's interrupt wake whoever waits for it rather than them spinning on the
+    /// used ring. Should be done before the device is set up, so no interrupt is missed.
+    pub fn enable_interrupts(&mut self, spec: IrqSpec) -> Result<(), ()> {
+        let base = self.base();
+        exceptions::without_interrupts(|| {
+            let mut interrupts = INTERRUPTS.lock();
+            interrupts.retain(|i| i.base != base);
+            interrupts.push(Interrupts {
+                base,
+                irq: spec.irq,
+                waiters: Vec::new(),
+                on_config_change: None,
+            });
+        });
+        if let Err(()) = gic::register_handler(spec, on_interrupt, base) {
+            exceptions::without_interrupts(|| INTERRUPTS.lock().retain(|i| i.base != base));
+            return Err(());
         }
+        Ok(())
     }
-}
-
-#[derive(Copy, Clone, Debug)]
-#[repr(C, packed)]
-struct VirtQUsedElement {
-    id: Endian<u16, Little>,
-    len: Endian<u16, Little>,
-}
 
-impl VirtQUsedElement {
-    pub const fn empty() -> VirtQUsedElement {
-        VirtQUsedElement {
-            id: Endian::from_raw(0),
-            len: Endian::from_raw(0),
+    /// Undoes `enable_interrupts`, masking the device's interrupt and forgetting its handler.
+    pub fn disable_interrupts(&mut self) {
+        let base = self.base();
+        let removed = exceptions::without_interrupts(|| {
+            let mut interrupts = INTERRUPTS.lock();
+            let i = interrupts.iter().position(|i| i.base == base)?;
+            Some(interrupts.remove(i))
+        });
+        if let Some(i) = removed {
+            gic::unregister_handler(i.irq);
         }
     }
-}
 
-#[derive(Copy, Clone, Debug)]
-#[repr(C, align(4))]
-pub struct VirtQUsed {
-    flags: Endian<u16, Little>,
-    idx: Endian<u16, Little>,
-    ring: [VirtQUsedElement; 128],
-    avail_event: Endian<u16, Little>,
-}
+    /// Calls `handler` whenever the device reports its configuration changed. Does nothing
+    /// unless the device's interrupts are enabled.
+    pub fn set_config_handler(&mut self, handler: ConfigHandler) {
+        let base = self.base();
+        exceptions::without_interrupts(|| {
+            if let Some(i) = INTERRUPTS.lock().iter_mut().find(|i| i.base == base) {
+                i.on_config_change = Some(handler);
+            }
+        });
+    }
 
-impl VirtQUsed {
-    pub const fn empty() -> VirtQUsed {
-        VirtQUsed {
-            flags: Endian::from_raw(0),
-            idx: Endian::from_raw(0),
-            ring: [VirtQUsedElement::empty(); 128],
-            avail_event: Endian::from_raw(0),
+    /// Waits for the device to finish `token` in `queue`, returning the number of bytes it
+    /// wrote. Other threads run in the meantime when the device has interrupts.
+    pub fn wait(&mut self, queue: &mut Virtqueue, token: Token) -> u32 {
+        let base = self.base();
+        loop {
+            let done = exceptions::without_interrupts(|| {
+                let current = sched::current()?;
+                {
+                    let mut interrupts = INTERRUPTS.lock();
+                    let i = interrupts.iter_mut().find(|i| i.base == base)?;
+                    // Checked with the lock held, so the handler can't slip in between.
+                    if let Some(written) = queue.poll(token) {
+                        return Some(Some(written));
+                    }
+                    i.waiters.push(current);
+                }
+                // Interrupts stay masked until we are off the cpu, so the wakeup cannot be
+                // missed.
+                unsafe { sched::block_current() };
+                Some(None)
+            });
+            match done {
+                Some(Some(written)) => return written,
+                Some(None) => continue,
+                // Nothing would wake us up, so spin instead.
+                None => return queue.wait(token),
+            }
         }
     }
-}
 
-impl VirtIORegs {
-    pub unsafe fn new<'a>(base: *mut VirtIORegs) -> Option<&'a mut VirtIORegs> {
-        let candidate = &mut *base;
-        if candidate.magic.native() == MAGIC
-            && candidate.version.native() == 2
-            && candidate.device_id.native() != DeviceId::Invalid as u32
-        {
-            Some(candidate)
-        } else {
-            None
-        }
+    /// Tells the device there are new buffers in `queue`.
+    pub fn notify(&mut self, queue: u16) {
+        mb();
+        unsafe { write_volatile(&mut self.queue_notify, (queue as u32).into()) };
     }
 
     pub fn device_id(&self) -> DeviceId {
@@ -186,97 +223,284 @@ impl VirtIORegs {
             _ => DeviceId::Invalid,
         }
     }
-}
 
-pub trait VirtIODevice<'a>: Sized {
-    unsafe fn new(
-        regs: &'a mut VirtIORegs,
-        desc: &'a mut [VirtQDesc],
-        avail: &'a mut VirtQAvailable,
-        used: &'a mut VirtQUsed,
-    ) -> Self;
-
-    fn init(
-        regs: &'a mut VirtIORegs,
-        desc: &'a mut [VirtQDesc],
-        avail: &'a mut VirtQAvailable,
-        used: &'a mut VirtQUsed,
-    ) -> Option<Self> {
+    fn status(&mut self) -> u32 {
+        unsafe { read_volatile(&self.status).native() }
+    }
+
+    /// Adds `status` to the bits already set.
+    fn set_status(&mut self, status: Status) {
+        let bits = self.status() | status as u32;
+        unsafe { write_volatile(&mut self.status, bits.into()) };
+        mb();
+    }
+
+    /// Marks the device as failed and stops listening to it, returning `err` for the caller.
+    fn fail(&mut self, err: InitErr) -> InitErr {
+        self.set_status(Status::Failed);
+        self.disable_interrupts();
+        err
+    }
+
+    /// Selects `queue`, returning the most descriptors it can have or `None` if the device
+    /// doesn't have it or it is already in use.
+    fn select_queue(&mut self, queue: u16) -> Option<usize> {
         unsafe {
-            write_volatile(&mut regs.status, Status::Reset.into());
-            mb();
-            write_volatile(&mut regs.status, Status::Acknowledge.into());
-            mb();
-            write_volatile(&mut regs.status, Status::Driver.into());
+            write_volatile(&mut self.queue_sel, (queue as u32).into());
             mb();
+            let max = read_volatile(&self.queue_num_max).native() as usize;
+            if max == 0 || read_volatile(&self.queue_ready).native() != 0 {
+                return None;
+            }
+            Some(max)
+        }
+    }
 
-            write_volatile(&mut regs.device_features_sel, 0.into());
-            mb();
-            let device_features = read_volatile(&mut regs.device_features).native();
-            write_volatile(&mut regs.driver_features_sel, 0.into());
+    /// Gives the selected queue to the device.
+    fn set_queue(&mut self, queue: &Virtqueue) {
+        let (desc, avail, used) = queue.addresses();
+        unsafe {
+            write_volatile(&mut self.queue_num, (queue.size() as u32).into());
+            write_volatile(&mut self.queue_desc_low, (desc as u32).into());
+            write_volatile(&mut self.queue_desc_high, ((desc >> 32) as u32).into());
+            write_volatile(&mut self.queue_avail_low, (avail as u32).into());
+            write_volatile(&mut self.queue_avail_high, ((avail >> 32) as u32).into());
+            write_volatile(&mut self.queue_used_low, (used as u32).into());
+            write_volatile(&mut self.queue_used_high, ((used >> 32) as u32).into());
             mb();
-            write_volatile(
-                &mut regs.driver_features,
-                (BLK_DEVICE_FEATURES & device_features).into(),
-            );
+            write_volatile(&mut self.queue_ready, 1.into());
             mb();
+        }
+    }
 
-            write_volatile(&mut regs.status, Status::FeaturesOk.into());
-            mb();
-            if read_volatile(&mut regs.status).native() & (Status::FeaturesOk as u32) == 0 {
-                panic!("Coudln't set blk features");
+    fn interrupt_status(&self) -> u32 {
+        unsafe { read_volatile(&self.interrupt_status).native() }
+    }
+
+    fn ack_interrupt(&mut self, bits: u32) {
+        unsafe { write_volatile(&mut self.interrupt_ack, bits.into()) };
+        mb();
+    }
+
+    fn device_features(&mut self) -> u64 {
+        let mut features = 0;
+        for sel in 0..2 {
+            unsafe {
+                write_volatile(&mut self.device_features_sel, sel.into());
+                mb();
+                features |= (read_volatile(&self.device_features).native() as u64) << (32 * sel);
             }
+        }
+        features
+    }
 
-            write_volatile(&mut regs.queue_sel, 0.into());
-            mb();
-            write_volatile(&mut regs.queue_num, (desc.len() as u32).into());
-            write_volatile(
-                &mut regs.queue_desc_low,
-                ((desc.as_ptr() as usize) as u32).into(),
-            );
-            write_volatile(
-                &mut regs.queue_desc_high,
-                ((desc.as_ptr() as usize >> 32) as u32).into(),
-            );
-            write_volatile(&mut regs.queue_avail_low, (avail as *const _ as u32).into());
-            write_volatile(
-                &mut regs.queue_avail_high,
-                ((avail as *const _ as usize >> 32) as u32).into(),
-            );
-            write_volatile(&mut regs.queue_used_low, (used as *const _ as u32).into());
-            write_volatile(
-                &mut regs.queue_used_high,
-                ((used as *const _ as usize >> 32) as u32).into(),
-            );
-            mb();
-            write_volatile(&mut regs.queue_ready, 1.into());
-            mb();
+    fn set_driver_features(&mut self, features: u64) {
+        for sel in 0..2 {
+            unsafe {
+                write_volatile(&mut self.driver_features_sel, sel.into());
+                mb();
+                write_volatile(
+                    &mut self.driver_features,
+                    ((features >> (32 * sel)) as u32).into(),
+                );
+                mb();
+            }
+        }
+    }
+}
 
-            write_volatile(&mut regs.status, Status::DriverOk.into());
-            mb();
-            if read_volatile(&mut regs.status).native() & (Status::DriverOk as u32) == 0 {
-                panic!("Couldn't set blk features");
+/// Services the interrupt of the device at `base`.
+fn on_interrupt(_irq: u32, base: usize) {
+    let regs = unsafe { &mut *(base as *mut VirtIORegs) };
+    let pending = regs.interrupt_status();
+    regs.ack_interrupt(pending);
+    let on_config_change = {
+        let mut interrupts = INTERRUPTS.lock();
+        let i = match interrupts.iter_mut().find(|i| i.base == base) {
+            Some(i) => i,
+            None => return,
+        };
+        if pending & INT_USED_RING != 0 {
+            for waiter in i.waiters.drain(..) {
+                sched::wake(waiter);
             }
-            Some(Self::new(regs, desc, avail, used))
+        }
+        i.on_config_change
+    };
+    if pending & INT_CONFIG_CHANGE != 0 {
+        if regs.status() & Status::NeedsReset as u32 != 0 {
+            error!("virtio device at {:#x} needs to be reset", base);
+        }
+        match on_config_change {
+            Some(handler) => handler(regs),
+            None => info!("virtio device at {:#x} changed its configuration", base),
         }
     }
 }
 
 /// Feature Bit
-const fn fb(b: u8) -> u32 {
+const fn fb(b: u8) -> u64 {
     1 << b
 }
-const BLK_DEVICE_FEATURES: u32 = fb(1) | !fb(5) | fb(2);
+
+// Features any device type may offer
+/// The device may be given chains of indirect descriptor tables.
+pub const VIRTIO_F_INDIRECT_DESC: u64 = fb(28);
+/// The `used_event` and `avail_event` fields suppress notifications.
+pub const VIRTIO_F_EVENT_IDX: u64 = fb(29);
+/// The device follows version 1 of the spec rather than the legacy interface.
+pub const VIRTIO_F_VERSION_1: u64 = fb(32);
+/// The device goes through an IOMMU.
+pub const VIRTIO_F_ACCESS_PLATFORM: u64 = fb(33);
+pub const VIRTIO_F_RING_PACKED: u64 = fb(34);
+/// Buffers are used in the order they were made available.
+pub const VIRTIO_F_IN_ORDER: u64 = fb(35);
+
+// Block device features
+/// `size_max` in the config is the largest segment.
+pub const VIRTIO_BLK_F_SIZE_MAX: u64 = fb(1);
+/// `seg_max` in the config is the most segments in a request.
+pub const VIRTIO_BLK_F_SEG_MAX: u64 = fb(2);
+pub const VIRTIO_BLK_F_GEOMETRY: u64 = fb(4);
+/// The device is read only.
+pub const VIRTIO_BLK_F_RO: u64 = fb(5);
+/// `blk_size` in the config is the block size.
+pub const VIRTIO_BLK_F_BLK_SIZE: u64 = fb(6);
+pub const VIRTIO_BLK_F_FLUSH: u64 = fb(9);
+pub const VIRTIO_BLK_F_TOPOLOGY: u64 = fb(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitErr {
+    /// The device lacks features the driver cannot work without.
+    MissingFeatures(u64),
+    /// The device did not accept the features the driver picked.
+    FeaturesRejected,
+    /// The device doesn't have the queue, or it is already in use.
+    QueueUnavailable(u16),
+    /// The device failed to come up after the queues were set up.
+    DriverRejected,
+}
+
+pub trait VirtIODevice<'a>: Sized {
+    /// Features the driver knows how to use, of which the ones the device also offers are
+    /// negotiated.
+    const SUPPORTED_FEATURES: u64;
+    /// Features the driver cannot do without.
+    const REQUIRED_FEATURES: u64 = VIRTIO_F_VERSION_1;
+
+    /// Number of queues the driver uses, which are numbered from 0.
+    const NUM_QUEUES: usize;
+
+    /// Makes the driver once the device is running with `features` negotiated, with
+    /// `NUM_QUEUES` queues in order.
+    unsafe fn new(regs: &'a mut VirtIORegs, queues: Vec<Virtqueue<'a>>, features: u64) -> Self;
+
+    /// The features negotiated with the device.
+    fn features(&self) -> u64;
+
+    /// Sets up the device with a queue in each of the first `NUM_QUEUES` of `memory`, as large
+    /// as both the device and `QueueMemory` allow. On failure the device's interrupt, if it was
+    /// enabled, is given back.
+    fn init(regs: &'a mut VirtIORegs, memory: &'a mut [QueueMemory]) -> Result<Self, InitErr> {
+        assert!(memory.len() >= Self::NUM_QUEUES, "Not enough queue memory");
+        unsafe { write_volatile(&mut regs.status, Status::Reset.into()) };
+        mb();
+        regs.set_status(Status::Acknowledge);
+        regs.set_status(Status::Driver);
+
+        let device_features = regs.device_features();
+        let missing = Self::REQUIRED_FEATURES & !device_features;
+        if missing != 0 {
+            return Err(regs.fail(InitErr::MissingFeatures(missing)));
+        }
+        let features = Self::SUPPORTED_FEATURES & device_features;
+        regs.set_driver_features(features);
+        regs.set_status(Status::FeaturesOk);
+        if regs.status() & Status::FeaturesOk as u32 == 0 {
+            return Err(regs.fail(InitErr::FeaturesRejected));
+        }
+
+        let mut queues = Vec::with_capacity(Self::NUM_QUEUES);
+        for (i, mem) in memory.iter_mut().take(Self::NUM_QUEUES).enumerate() {
+            let max = match regs.select_queue(i as u16) {
+                Some(max) => max.min(MAX_QUEUE_SIZE),
+                None => {
+                    return Err(regs.fail(InitErr::QueueUnavailable(i as u16)));
+                }
+            };
+            // Split queues must be a power of two long.
+            let size = 1 << (usize::BITS - 1 - max.leading_zeros());
+            let queue = Virtqueue::from_memory(mem, size);
+            regs.set_queue(&queue);
+            queues.push(queue);
+        }
+
+        regs.set_status(Status::DriverOk);
+        if regs.status() & (Status::DriverOk as u32 | Status::NeedsReset as u32)
+            != Status::DriverOk as u32
+        {
+            return Err(regs.fail(InitErr::DriverRejected));
+        }
+        Ok(unsafe { Self::new(regs, queues, features) })
+    }
+}
+
+/// Size of the sectors requests are addressed in, whatever the device's block size.
+pub const SECTOR_SIZE: usize = 512;
+
+// Status the device writes at the end of a request
+const BLK_S_OK: u8 = 0;
+const BLK_S_IOERR: u8 = 1;
+const BLK_S_UNSUPP: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlkErr {
+    /// Buffers must be a nonzero number of whole sectors.
+    BadLength,
+    /// The buffer needs more segments than the device or queue takes in one request.
+    TooLarge,
+    /// The request goes past the end of the device.
+    OutOfRange,
+    ReadOnly,
+    /// Every descriptor is in use, so some request has to complete first.
+    Busy,
+    /// The device failed the request.
+    IoErr,
+    /// The device doesn't know the request.
+    Unsupported,
+    /// The device finished the request with a status the spec doesn't define.
+    BadStatus(u8),
+    /// The token doesn't belong to a request in flight on this device.
+    BadToken,
+}
+
+/// Identifies a block request until it has been waited for, which uses it up.
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlkToken(usize);
+
+/// The parts of a request in flight the device reads and writes besides the data.
+#[derive(Debug)]
+struct BlkRequest {
+    hdr: BlkReqHdr,
+    status: u8,
+    token: Option<Token>,
+}
 
 #[derive(Debug)]
 pub struct VirtIOBlk<'a> {
     pub regs: &'a mut VirtIORegs,
-    desc: &'a mut [VirtQDesc],
-    avail: &'a mut VirtQAvailable,
-    used: &'a mut VirtQUsed,
+    queue: Virtqueue<'a>,
+    features: u64,
+    /// One slot per descriptor, which is never resized so the device can keep pointing into it.
+    requests: Vec<BlkRequest>,
+    /// Largest data segment in a request.
+    size_max: usize,
+    /// Most data segments in a request.
+    seg_max: usize,
 }
 
 #[derive(Debug)]
+#[repr(C)]
 pub struct VirtIOBlkConfig {
     pub(crate) capacity: LEU64,
     size_max: LEU32,
@@ -306,6 +530,7 @@ pub struct VirtIOBlkConfig {
     _unused1: [u8; 3],
 }
 
+#[derive(Debug)]
 #[repr(C)]
 pub struct BlkReqHdr {
     pub req_type: LEU32,
@@ -314,19 +539,48 @@ pub struct BlkReqHdr {
 }
 
 impl<'a> VirtIODevice<'a> for VirtIOBlk<'a> {
-    unsafe fn new(
-        regs: &'a mut VirtIORegs,
-        desc: &'a mut [VirtQDesc],
-        avail: &'a mut VirtQAvailable,
-        used: &'a mut VirtQUsed,
-    ) -> Self {
+    const SUPPORTED_FEATURES: u64 = VIRTIO_F_VERSION_1
+        | VIRTIO_BLK_F_SIZE_MAX
+        | VIRTIO_BLK_F_SEG_MAX
+        | VIRTIO_BLK_F_BLK_SIZE
+        | VIRTIO_BLK_F_RO;
+
+    const NUM_QUEUES: usize = 1;
+
+    unsafe fn new(regs: &'a mut VirtIORegs, mut queues: Vec<Virtqueue<'a>>, features: u64) -> Self {
+        regs.set_config_handler(on_blk_config_change);
+        let config = VirtIOBlkConfig::read(regs);
+        let queue = queues.remove(0);
+        let requests = (0..queue.size())
+            .map(|_| BlkRequest {
+                hdr: BlkReqHdr::new(VirtIOBlkTy::Read, 0),
+                status: 0,
+                token: None,
+            })
+            .collect();
+        let size_max = match config.size_max.native() {
+            n if features & VIRTIO_BLK_F_SIZE_MAX != 0 && n != 0 => n as usize,
+            _ => u32::MAX as usize,
+        };
+        let seg_max = match config.seg_max.native() {
+            n if features & VIRTIO_BLK_F_SEG_MAX != 0 && n != 0 => n as usize,
+            _ => usize::MAX,
+        };
+        // The header and status take a descriptor each.
+        let seg_max = seg_max.min(queue.size().saturating_sub(2));
         VirtIOBlk {
+            queue,
             regs,
-            desc,
-            avail,
-            used,
+            features,
+            requests,
+            size_max,
+            seg_max,
         }
     }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
 }
 
 #[repr(u32)]
@@ -338,170 +592,203 @@ pub enum VirtIOBlkTy {
     WriteZero = 13,
 }
 
-impl<'a> VirtIOBlk<'a> {
-    pub fn read(&mut self, sector: u64, data: &mut [u8; 512]) {
+impl BlkReqHdr {
+    fn new(ty: VirtIOBlkTy, sector: u64) -> Self {
+        BlkReqHdr {
+            req_type: (ty as u32).into(),
+            reserved: 0,
+            sector: sector.into(),
+        }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
         unsafe {
-            let mut status: u8 = 0;
-            let blkreq_hdr = BlkReqHdr {
-                req_type: (VirtIOBlkTy::Read as u32).into(),
-                reserved: 0,
-                sector: sector.into(),
-            };
+            core::slice::from_raw_parts(
+                self as *const Self as *const u8,
+                core::mem::size_of::<Self>(),
+            )
+        }
+    }
+}
 
-            write_volatile(&mut status, 0);
-
-            write_volatile(
-                &mut self.desc[0],
-                VirtQDesc {
-                    addr: (&blkreq_hdr as *const _ as u64).into(),
-                    len: (core::mem::size_of::<BlkReqHdr>() as u32).into(),
-                    flags: (1).into(),
-                    next: 1.into(),
-                },
-            );
-
-            write_volatile(
-                &mut self.desc[1],
-                VirtQDesc {
-                    addr: (data.as_ptr() as *const _ as u64).into(),
-                    len: (512).into(),
-                    flags: 3.into(),
-                    next: 2.into(),
-                },
-            );
-
-            write_volatile(
-                &mut self.desc[2],
-                VirtQDesc {
-                    addr: (&status as *const _ as u64).into(),
-                    len: (1).into(),
-                    flags: 2.into(),
-                    next: 0.into(),
-                },
-            );
-
-            write_volatile(
-                &mut self.avail.ring[self.avail.idx.native() as usize % 128],
-                0.into(),
-            );
-            mb();
-            write_volatile(
-                &mut self.avail.idx,
-                (self.avail.idx.native().wrapping_add(1)).into(),
-            );
-            mb();
-            write_volatile(&mut self.regs.queue_notify, 0.into());
-            mb();
-            while read_volatile(&self.used.idx) != read_volatile(&self.avail.idx) {}
+impl VirtIOBlkConfig {
+    /// Reads the configuration of the block device, retrying if the device changes it midway.
+    pub fn read(regs: &VirtIORegs) -> Self {
+        loop {
+            let generation = unsafe { read_volatile(&regs.config_generation) }.native();
+            let config =
+                unsafe { read_volatile(&regs.config as *const LEU64 as *const VirtIOBlkConfig) };
+            if unsafe { read_volatile(&regs.config_generation) }.native() == generation {
+                return config;
+            }
         }
     }
+}
 
-    pub fn write(&mut self, sector: u64, data: &[u8; 512]) {
-        unsafe {
-            let mut status: u8 = 0;
-            let blkreq_hdr = BlkReqHdr {
-                req_type: (VirtIOBlkTy::Write as u32).into(),
-                reserved: 0,
-                sector: sector.into(),
-            };
+fn on_blk_config_change(regs: &mut VirtIORegs) {
+    let config = VirtIOBlkConfig::read(regs);
+    info!(
+        "virtio-blk capacity is now {} sectors",
+        config.capacity.native()
+    );
+}
 
-            write_volatile(&mut status, 0);
-
-            write_volatile(
-                &mut self.desc[0],
-                VirtQDesc {
-                    addr: (&blkreq_hdr as *const _ as u64).into(),
-                    len: (core::mem::size_of::<BlkReqHdr>() as u32).into(),
-                    flags: (1).into(),
-                    next: 1.into(),
-                },
-            );
-
-            write_volatile(
-                &mut self.desc[1],
-                VirtQDesc {
-                    addr: (data.as_ptr() as *const _ as u64).into(),
-                    len: (512).into(),
-                    flags: (1).into(),
-                    next: 2.into(),
-                },
-            );
-
-            write_volatile(
-                &mut self.desc[2],
-                VirtQDesc {
-                    addr: (&status as *const _ as u64).into(),
-                    len: (1).into(),
-                    flags: 2.into(),
-                    next: 0.into(),
-                },
-            );
-
-            write_volatile(
-                &mut self.avail.ring[self.avail.idx.native() as usize % 128],
-                0.into(),
-            );
-            mb();
-            write_volatile(
-                &mut self.avail.idx,
-                (self.avail.idx.native().wrapping_add(1)).into(),
-            );
-            mb();
-            write_volatile(&mut self.regs.queue_notify, 0.into());
-            mb();
-            while read_volatile(&self.used.idx) != read_volatile(&self.avail.idx) {}
+impl<'a> VirtIOBlk<'a> {
+    /// Number of sectors on the device, which may change while it is in use.
+    pub fn capacity(&self) -> u64 {
+        VirtIOBlkConfig::read(self.regs).capacity.native()
+    }
+
+    /// Checks a request for `len` bytes at `sector`, returning a free slot for it.
+    fn prepare(&self, sector: u64, len: usize) -> Result<usize, BlkErr> {
+        if len == 0 || len % SECTOR_SIZE != 0 {
+            return Err(BlkErr::BadLength);
+        }
+        if (len + self.size_max - 1) / self.size_max > self.seg_max {
+            return Err(BlkErr::TooLarge);
+        }
+        let end = sector.checked_add((len / SECTOR_SIZE) as u64);
+        if end.map_or(true, |end| end > self.capacity()) {
+            return Err(BlkErr::OutOfRange);
+        }
+        self.requests
+            .iter()
+            .position(|r| r.token.is_none())
+            .ok_or(BlkErr::Busy)
+    }
+
+    /// Starts reading the sectors from `sector` on into `data`, which may be many sectors long.
+    ///
+    /// # Safety
+    /// `data` must stay alive and untouched until the request has been waited for.
+    pub unsafe fn submit_read(&mut self, sector: u64, data: &mut [u8]) -> Result<BlkToken, BlkErr> {
+        let slot = self.prepare(sector, data.len())?;
+        let req = &mut self.requests[slot];
+        req.hdr = BlkReqHdr::new(VirtIOBlkTy::Read, sector);
+        // Anything but what the device writes back is a failure.
+        req.status = u8::MAX;
+        let mut writable: Vec<&mut [u8]> = data.chunks_mut(self.size_max).collect();
+        writable.push(core::slice::from_mut(&mut req.status));
+        let token = self
+            .queue
+            .add(&[req.hdr.as_bytes()], &mut writable)
+            .map_err(|_| BlkErr::Busy)?;
+        req.token = Some(token);
+        self.regs.notify(0);
+        Ok(BlkToken(slot))
+    }
+
+    /// Starts writing `data`, which may be many sectors long, to the sectors from `sector` on.
+    ///
+    /// # Safety
+    /// `data` must stay alive until the request has been waited for.
+    pub unsafe fn submit_write(&mut self, sector: u64, data: &[u8]) -> Result<BlkToken, BlkErr> {
+        if self.features & VIRTIO_BLK_F_RO != 0 {
+            return Err(BlkErr::ReadOnly);
+        }
+        let slot = self.prepare(sector, data.len())?;
+        let req = &mut self.requests[slot];
+        req.hdr = BlkReqHdr::new(VirtIOBlkTy::Write, sector);
+        req.status = u8::MAX;
+        let mut readable = Vec::with_capacity(1 + data.len() / self.size_max);
+        readable.push(req.hdr.as_bytes());
+        readable.extend(data.chunks(self.size_max));
+        let token = self
+            .queue
+            .add(&readable, &mut [core::slice::from_mut(&mut req.status)])
+            .map_err(|_| BlkErr::Busy)?;
+        req.token = Some(token);
+        self.regs.notify(0);
+        Ok(BlkToken(slot))
+    }
+
+    /// Frees the slot of a completed request and turns its status into a result.
+    fn finish(&mut self, slot: usize) -> Result<(), BlkErr> {
+        let req = &mut self.requests[slot];
+        req.token = None;
+        match unsafe { read_volatile(&req.status) } {
+            BLK_S_OK => Ok(()),
+            BLK_S_IOERR => Err(BlkErr::IoErr),
+            BLK_S_UNSUPP => Err(BlkErr::Unsupported),
+            status => Err(BlkErr::BadStatus(status)),
+        }
+    }
+
+    /// The queue token of the request in flight in `slot`.
+    fn in_flight(&self, slot: usize) -> Result<Token, BlkErr> {
+        self.requests
+            .get(slot)
+            .and_then(|r| r.token)
+            .ok_or(BlkErr::BadToken)
+    }
+
+    /// Returns the outcome of the request if the device is done with it, or hands the token
+    /// back if it isn't.
+    pub fn poll(&mut self, token: BlkToken) -> Result<Result<(), BlkErr>, BlkToken> {
+        let queue_token = match self.in_flight(token.0) {
+            Ok(queue_token) => queue_token,
+            Err(e) => return Ok(Err(e)),
+        };
+        if self.queue.poll(queue_token).is_none() {
+            return Err(token);
         }
+        Ok(self.finish(token.0))
+    }
+
+    /// Waits for the device to be done with the request.
+    pub fn wait(&mut self, BlkToken(slot): BlkToken) -> Result<(), BlkErr> {
+        let token = self.in_flight(slot)?;
+        self.regs.wait(&mut self.queue, token);
+        self.finish(slot)
+    }
+
+    pub fn read(&mut self, sector: u64, data: &mut [u8]) -> Result<(), BlkErr> {
+        let token = unsafe { self.submit_read(sector, data)? };
+        self.wait(token)
+    }
+
+    pub fn write(&mut self, sector: u64, data: &[u8]) -> Result<(), BlkErr> {
+        let token = unsafe { self.submit_write(sector, data)? };
+        self.wait(token)
     }
 }
 
 pub struct VirtIOEntropy<'a> {
     regs: &'a mut VirtIORegs,
-    desc: &'a mut [VirtQDesc],
-    avail: &'a mut VirtQAvailable,
-    used: &'a mut VirtQUsed,
+    queue: Virtqueue<'a>,
+    features: u64,
 }
 
 impl<'a> VirtIODevice<'a> for VirtIOEntropy<'a> {
-    unsafe fn new(
-        regs: &'a mut VirtIORegs,
-        desc: &'a mut [VirtQDesc],
-        avail: &'a mut VirtQAvailable,
-        used: &'a mut VirtQUsed,
-    ) -> Self {
+    const SUPPORTED_FEATURES: u64 = VIRTIO_F_VERSION_1;
+
+    const NUM_QUEUES: usize = 1;
+
+    unsafe fn new(regs: &'a mut VirtIORegs, mut queues: Vec<Virtqueue<'a>>, features: u64) -> Self {
         VirtIOEntropy {
+            queue: queues.remove(0),
             regs,
-            desc,
-            avail,
-            used,
+            features,
         }
     }
+
+    fn features(&self) -> u64 {
+        self.features
+    }
 }
 
 impl<'a> VirtIOEntropy<'a> {
     pub fn read(&mut self, data: &mut [u8]) {
+        if data.is_empty() {
+            return;
+        }
         unsafe {
-            write_volatile(
-                &mut self.desc[0],
-                VirtQDesc {
-                    addr: (data.as_ptr() as *const _ as u64).into(),
-                    len: (data.len() as u32).into(),
-                    flags: (2).into(),
-                    next: 0.into(),
-                },
-            );
-
-            write_volatile(
-                &mut self.avail.ring[self.avail.idx.native() as usize],
-                0.into(),
-            );
-            mb();
-            write_volatile(&mut self.avail.idx, (self.avail.idx.native() + 1).into());
-            mb();
-            write_volatile(&mut self.regs.queue_notify, 0.into());
-            mb();
-            while read_volatile(&self.used.idx).native() != read_volatile(&self.avail.idx).native()
-            {
-            }
+            let token = self
+                .queue
+                .add(&[], &mut [data])
+                .expect("Entropy requests are made one at a time");
+            self.regs.notify(0);
+            self.regs.wait(&mut self.queue, token);
         }
     }
 }
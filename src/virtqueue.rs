@@ -0,0 +1,300 @@
+use crate::utils::*;
+use core::ptr::{read_volatile, write_volatile};
+
+/// Most descriptors a queue can have, which is the length of the rings.
+pub const MAX_QUEUE_SIZE: usize = 128;
+
+/// The chain continues at `next`.
+const DESC_F_NEXT: u16 = 1;
+/// The device writes into the buffer instead of reading it.
+const DESC_F_WRITE: u16 = 2;
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C, align(16))]
+pub struct VirtQDesc {
+    addr: Endian<u64, Little>,
+    len: Endian<u32, Little>,
+    flags: Endian<u16, Little>,
+    next: Endian<u16, Little>,
+}
+
+impl VirtQDesc {
+    pub const fn empty() -> VirtQDesc {
+        VirtQDesc {
+            addr: Endian::from_raw(0),
+            len: Endian::from_raw(0),
+            flags: Endian::from_raw(0),
+            next: Endian::from_raw(0),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C, align(2))]
+pub struct VirtQAvailable {
+    flags: Endian<u16, Little>,
+    idx: Endian<u16, Little>,
+    ring: [Endian<u16, Little>; MAX_QUEUE_SIZE],
+    used_event: Endian<u16, Little>,
+}
+
+impl VirtQAvailable {
+    pub const fn empty() -> VirtQAvailable {
+        VirtQAvailable {
+            flags: Endian::from_raw(0),
+            idx: Endian::from_raw(0),
+            ring: [Endian::from_raw(0); MAX_QUEUE_SIZE],
+            used_event: Endian::from_raw(0),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+struct VirtQUsedElement {
+    /// Head of the chain the device is done with.
+    id: Endian<u32, Little>,
+    /// Number of bytes written into the chain.
+    len: Endian<u32, Little>,
+}
+
+impl VirtQUsedElement {
+    pub const fn empty() -> VirtQUsedElement {
+        VirtQUsedElement {
+            id: Endian::from_raw(0),
+            len: Endian::from_raw(0),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C, align(4))]
+pub struct VirtQUsed {
+    flags: Endian<u16, Little>,
+    idx: Endian<u16, Little>,
+    ring: [VirtQUsedElement; MAX_QUEUE_SIZE],
+    avail_event: Endian<u16, Little>,
+}
+
+impl VirtQUsed {
+    pub const fn empty() -> VirtQUsed {
+        VirtQUsed {
+            flags: Endian::from_raw(0),
+            idx: Endian::from_raw(0),
+            ring: [VirtQUsedElement::empty(); MAX_QUEUE_SIZE],
+            avail_event: Endian::from_raw(0),
+        }
+    }
+}
+
+/// Memory for one queue of up to `MAX_QUEUE_SIZE` descriptors, which has to stay put while the
+/// device uses it.
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct QueueMemory {
+    desc: [VirtQDesc; MAX_QUEUE_SIZE],
+    avail: VirtQAvailable,
+    used: VirtQUsed,
+}
+
+impl QueueMemory {
+    pub const fn empty() -> QueueMemory {
+        QueueMemory {
+            desc: [VirtQDesc::empty(); MAX_QUEUE_SIZE],
+            avail: VirtQAvailable::empty(),
+            used: VirtQUsed::empty(),
+        }
+    }
+}
+
+/// Identifies a request made with `Virtqueue::add` until it is completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token(u16);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueErr {
+    /// A chain needs at least one buffer.
+    NoBuffers,
+    /// There are not enough free descriptors until some requests complete.
+    Full,
+}
+
+/// A split virtqueue: the descriptor table, the ring of chains made available to the device and
+/// the ring of chains it has used.
+#[derive(Debug)]
+pub struct Virtqueue<'a> {
+    desc: &'a mut [VirtQDesc],
+    avail: &'a mut VirtQAvailable,
+    used: &'a mut VirtQUsed,
+    /// First unused descriptor, the rest of the free list is linked through `next`.
+    free_head: u16,
+    num_free: u16,
+    /// The `used.idx` up to which entries have been reaped.
+    last_used: u16,
+    /// Length of the chain in flight at each head descriptor, 0 for none.
+    chain_len: [u16; MAX_QUEUE_SIZE],
+    /// Bytes written by the device into each completed chain, until its token is polled.
+    completed: [Option<u32>; MAX_QUEUE_SIZE],
+}
+
+impl<'a> Virtqueue<'a> {
+    /// Makes a queue of `desc.len()` descriptors, at most `MAX_QUEUE_SIZE`.
+    pub fn new(
+        desc: &'a mut [VirtQDesc],
+        avail: &'a mut VirtQAvailable,
+        used: &'a mut VirtQUsed,
+    ) -> Self {
+        assert!(!desc.is_empty() && desc.len() <= MAX_QUEUE_SIZE);
+        for (i, d) in desc.iter_mut().enumerate() {
+            *d = VirtQDesc::empty();
+            d.next = ((i + 1) as u16).into();
+        }
+        *avail = VirtQAvailable::empty();
+        *used = VirtQUsed::empty();
+        let num_free = desc.len() as u16;
+        Virtqueue {
+            desc,
+            avail,
+            used,
+            free_head: 0,
+            num_free,
+            last_used: 0,
+            chain_len: [0; MAX_QUEUE_SIZE],
+            completed: [None; MAX_QUEUE_SIZE],
+        }
+    }
+
+    /// Makes a queue of `size` descriptors in `mem`.
+    pub fn from_memory(mem: &'a mut QueueMemory, size: usize) -> Self {
+        Self::new(&mut mem.desc[..size], &mut mem.avail, &mut mem.used)
+    }
+
+    /// Number of descriptors, which is also the length of both rings.
+    pub fn size(&self) -> usize {
+        self.desc.len()
+    }
+
+    pub fn num_free(&self) -> usize {
+        self.num_free as usize
+    }
+
+    /// Addresses of the descriptor table, available ring and used ring, for the transport.
+    pub fn addresses(&self) -> (u64, u64, u64) {
+        (
+            self.desc.as_ptr() as u64,
+            &*self.avail as *const _ as u64,
+            &*self.used as *const _ as u64,
+        )
+    }
+
+    /// Makes a chain of the `readable` buffers followed by the `writable` ones available to the
+    /// device. The device still has to be notified.
+    ///
+    /// # Safety
+    /// Every buffer must stay alive, and the writable ones unaliased, until the returned token
+    /// has been polled as complete.
+    pub unsafe fn add(
+        &mut self,
+        readable: &[&[u8]],
+        writable: &mut [&mut [u8]],
+    ) -> Result<Token, QueueErr> {
+        let count = readable.len() + writable.len();
+        if count == 0 {
+            return Err(QueueErr::NoBuffers);
+        }
+        if count > self.num_free as usize {
+            return Err(QueueErr::Full);
+        }
+        let buffers = readable
+            .iter()
+            .map(|b| (b.as_ptr() as u64, b.len(), 0))
+            .chain(
+                writable
+                    .iter_mut()
+                    .map(|b| (b.as_mut_ptr() as u64, b.len(), DESC_F_WRITE)),
+            );
+        let head = self.free_head;
+        let mut curr = head;
+        for (i, (addr, len, flags)) in buffers.enumerate() {
+            let desc = &mut self.desc[curr as usize];
+            // Free descriptors are linked in the same way, so the chain just takes them in order.
+            let next = read_volatile(desc).next;
+            let flags = if i + 1 < count {
+                flags | DESC_F_NEXT
+            } else {
+                flags
+            };
+            write_volatile(
+                desc,
+                VirtQDesc {
+                    addr: addr.into(),
+                    len: (len as u32).into(),
+                    flags: flags.into(),
+                    next,
+                },
+            );
+            curr = next.native();
+        }
+        self.free_head = curr;
+        self.num_free -= count as u16;
+        self.chain_len[head as usize] = count as u16;
+
+        let idx = read_volatile(&self.avail.idx).native();
+        write_volatile(
+            &mut self.avail.ring[idx as usize % self.size()],
+            head.into(),
+        );
+        // The device must see the chain before the index that makes it available.
+        mb();
+        write_volatile(&mut self.avail.idx, idx.wrapping_add(1).into());
+        mb();
+        Ok(Token(head))
+    }
+
+    /// Notes every chain the device has finished with since the last call.
+    fn reap(&mut self) {
+        let used_idx = unsafe { read_volatile(&self.used.idx) }.native();
+        // Entries are only valid once their index has been seen.
+        mb();
+        while self.last_used != used_idx {
+            let slot = self.last_used as usize % self.size();
+            let elem = unsafe { read_volatile(&self.used.ring[slot]) };
+            let id = elem.id.native() as usize;
+            if id < self.size() && self.chain_len[id] != 0 {
+                self.completed[id] = Some(elem.len.native());
+            }
+            self.last_used = self.last_used.wrapping_add(1);
+        }
+    }
+
+    /// Returns the number of bytes the device wrote if it is done with `token`, whose
+    /// descriptors are then free for new requests.
+    pub fn poll(&mut self, token: Token) -> Option<u32> {
+        self.reap();
+        let written = self.completed[token.0 as usize].take()?;
+        self.free_chain(token.0);
+        Some(written)
+    }
+
+    /// Spins until the device is done with `token`, returning the number of bytes it wrote.
+    pub fn wait(&mut self, token: Token) -> u32 {
+        loop {
+            if let Some(written) = self.poll(token) {
+                return written;
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Puts the chain starting at `head` back on the free list.
+    fn free_chain(&mut self, head: u16) {
+        let count = core::mem::replace(&mut self.chain_len[head as usize], 0);
+        let mut last = head;
+        for _ in 1..count {
+            last = self.desc[last as usize].next.native();
+        }
+        self.desc[last as usize].next = self.free_head.into();
+        self.free_head = head;
+        self.num_free += count;
+    }
+}